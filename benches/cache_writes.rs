@@ -0,0 +1,94 @@
+//! Compares writing an artist's tracks to the cache one row at a time against
+//! writing them all in one statement. Needs `DATABASE_URL` to point to a migrated
+//! database; everything is rolled back afterwards.
+
+use std::env;
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use music_quiz::db::{AlbumInfo, ArtistInfo, TrackInfo};
+use sqlx::{PgConnection, PgPool};
+use tokio::runtime::Runtime;
+
+const ARTIST: i64 = 1_999_999_999;
+const ALBUM: i64 = 1_999_999_999;
+
+fn tracks(n: i64) -> Vec<TrackInfo> {
+    (0..n)
+        .map(|i| TrackInfo {
+            id: 1_000_000_000_000 + i,
+            album_title: "Benchmark Album".to_owned(),
+            album_cover_url: String::new(),
+            album_id: ALBUM,
+            album_release_date: None,
+            title: format!("Benchmark Track {i}"),
+            preview_url: format!("https://example.com/preview/{i}.mp3"),
+            duration: Some(200),
+            rank: Some(100_000),
+            explicit: Some(false),
+            track_position: Some(i as i32 + 1),
+            disc_number: Some(1),
+            isrc: Some(format!("XX0000{i:07}")),
+        })
+        .collect()
+}
+
+async fn add_album(conn: &mut PgConnection) {
+    ArtistInfo {
+        id: ARTIST,
+        title: "Benchmark Artist".to_owned(),
+        icon_url: String::new(),
+        updated_at: Utc::now(),
+    }
+    .upsert(conn)
+    .await
+    .unwrap();
+    AlbumInfo {
+        id: ALBUM,
+        artist: ARTIST,
+        title: "Benchmark Album".to_owned(),
+        cover_url: String::new(),
+        release_date: None,
+    }
+    .upsert(conn)
+    .await
+    .unwrap();
+}
+
+async fn write_per_row(pool: &PgPool, tracks: &[TrackInfo]) {
+    let mut trans = pool.begin().await.unwrap();
+    add_album(&mut trans).await;
+    for track in tracks {
+        track.upsert(&mut trans).await.unwrap();
+    }
+    trans.rollback().await.unwrap();
+}
+
+async fn write_bulk(pool: &PgPool, tracks: &[TrackInfo]) {
+    let mut trans = pool.begin().await.unwrap();
+    add_album(&mut trans).await;
+    TrackInfo::upsert_many(&mut trans, tracks).await.unwrap();
+    trans.rollback().await.unwrap();
+}
+
+fn cache_writes(c: &mut Criterion) {
+    let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let rt = Runtime::new().unwrap();
+    let pool = rt.block_on(PgPool::connect(&url)).unwrap();
+
+    let mut group = c.benchmark_group("cache_writes");
+    group.sample_size(10);
+    for n in [100, 2000] {
+        let tracks = tracks(n);
+        group.bench_with_input(BenchmarkId::new("per_row", n), &tracks, |b, tracks| {
+            b.to_async(&rt).iter(|| write_per_row(&pool, tracks))
+        });
+        group.bench_with_input(BenchmarkId::new("bulk", n), &tracks, |b, tracks| {
+            b.to_async(&rt).iter(|| write_bulk(&pool, tracks))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, cache_writes);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+-- Add migration script here
+CREATE TABLE quiz_sessions (
+    id TEXT PRIMARY KEY,
+    artist BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    finished BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX quiz_sessions_artist_idx ON quiz_sessions(artist);
+
+CREATE TABLE quiz_answers (
+    session TEXT NOT NULL REFERENCES quiz_sessions(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    token TEXT NOT NULL,
+    -- not a foreign key, as tracks get replaced when an artist's cache is refreshed
+    track BIGINT NOT NULL,
+    answer TEXT NOT NULL,
+    options TEXT[] NOT NULL,
+    chosen TEXT,
+    correct BOOLEAN,
+    asked_at TIMESTAMPTZ,
+    answered_at TIMESTAMPTZ,
+    response_ms INTEGER,
+    PRIMARY KEY (session, position),
+    UNIQUE (session, token)
+);
@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE quiz_sessions
+    ADD COLUMN player_name TEXT,
+    ADD COLUMN submitted_at TIMESTAMPTZ;
+
+CREATE INDEX quiz_sessions_leaderboard_idx ON quiz_sessions(artist) WHERE player_name IS NOT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE artists
+    ADD COLUMN last_accessed_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE INDEX artists_last_accessed_idx ON artists(last_accessed_at);
@@ -0,0 +1,11 @@
+-- not every source knows all of these, so they're all nullable
+ALTER TABLE albums
+    ADD COLUMN release_date DATE;
+
+ALTER TABLE tracks
+    ADD COLUMN duration INTEGER,
+    ADD COLUMN rank INTEGER,
+    ADD COLUMN explicit BOOLEAN,
+    ADD COLUMN track_position INTEGER,
+    ADD COLUMN disc_number INTEGER,
+    ADD COLUMN isrc TEXT;
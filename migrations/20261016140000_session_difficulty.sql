@@ -0,0 +1,3 @@
+-- sessions from before difficulties existed asked about every track
+ALTER TABLE quiz_sessions
+    ADD COLUMN difficulty TEXT NOT NULL DEFAULT 'normal';
@@ -0,0 +1,3 @@
+-- sessions from before distractor strategies existed picked wrong options at random
+ALTER TABLE quiz_sessions
+    ADD COLUMN distractors TEXT NOT NULL DEFAULT 'random';
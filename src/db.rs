@@ -1,8 +1,34 @@
-use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::PgConnection;
 
-use crate::deezer::{Album, Artist, Track};
+use crate::difficulty::Difficulty;
+use crate::distractors::DistractorStrategy;
+
+/// What an upsert did to the row it was given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upsert {
+    /// The row didn't exist, and was inserted.
+    Inserted,
+    /// The row existed with different values, and was updated.
+    Updated,
+    /// The row existed with the same values, and was left alone.
+    Unchanged,
+}
+
+impl Upsert {
+    /// Gets the outcome from the `inserted` column returned by an upsert, which
+    /// returns no row if nothing changed.
+    fn from_returned(inserted: Option<bool>) -> Self {
+        match inserted {
+            Some(true) => Upsert::Inserted,
+            Some(false) => Upsert::Updated,
+            None => Upsert::Unchanged,
+        }
+    }
+}
 
 /// Represents info about an artist, corresponding with the `artists` table in the database
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -42,26 +68,112 @@ impl ArtistInfo {
         Ok(())
     }
 
-    /// Deletes `self` from the database
-    pub async fn delete(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
-        sqlx::query!("delete from artists where id = $1", self.id)
-            .execute(conn)
-            .await?;
+    /// Inserts `self` into the database, or updates it if it's already there.
+    pub async fn upsert(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "insert into artists (id, title, icon_url, updated_at) values ($1, $2, $3, $4)
+             on conflict (id) do update set
+                 title = excluded.title,
+                 icon_url = excluded.icon_url,
+                 updated_at = excluded.updated_at",
+            self.id,
+            self.title,
+            self.icon_url,
+            self.updated_at
+        )
+        .execute(conn)
+        .await?;
         Ok(())
     }
-}
 
-impl From<Artist> for ArtistInfo {
-    fn from(a: Artist) -> Self {
-        Self {
-            id: a.id.into(),
-            title: a.name,
-            icon_url: a.picture_medium.to_string(),
-            updated_at: Utc::now(),
-        }
+    /// Gets the ids of up to `limit` artists last updated before `updated_before`
+    /// that were played or accessed since `played_since`, the most played first and
+    /// then the most recently accessed.
+    pub async fn due_for_refresh(
+        conn: &mut PgConnection,
+        updated_before: DateTime<Utc>,
+        played_since: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<i64>, sqlx::Error> {
+        sqlx::query_scalar!(
+            "select artists.id
+             from artists
+                 left join quiz_sessions
+                     on quiz_sessions.artist = artists.id and quiz_sessions.created_at > $2
+             where artists.updated_at < $1
+             group by artists.id
+             having count(quiz_sessions.id) > 0 or artists.last_accessed_at > $2
+             order by count(quiz_sessions.id) desc, artists.last_accessed_at desc
+             limit $3",
+            updated_before,
+            played_since,
+            limit
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Records that the artist with id `id` was accessed at `now`, unless that was
+    /// already recorded since `recorded_since`, so that this isn't written on every request.
+    pub async fn touch(
+        conn: &mut PgConnection,
+        id: u32,
+        now: DateTime<Utc>,
+        recorded_since: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "update artists set last_accessed_at = $2 where id = $1 and last_accessed_at < $3",
+            i64::from(id),
+            now,
+            recorded_since
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Gets the artists last accessed before `accessed_before`, along with all but
+    /// the `keep` most recently accessed ones, least recently accessed first.
+    pub async fn unused(
+        conn: &mut PgConnection,
+        accessed_before: DateTime<Utc>,
+        keep: i64,
+    ) -> Result<Vec<UnusedArtist>, sqlx::Error> {
+        sqlx::query_as!(
+            UnusedArtist,
+            r#"select id as "id!", title as "title!", last_accessed_at as "last_accessed_at!"
+             from (
+                 select id, title, last_accessed_at,
+                     row_number() over (order by last_accessed_at desc, id) as rank
+                 from artists
+             ) ranked
+             where last_accessed_at < $1 or rank > $2
+             order by last_accessed_at, id"#,
+            accessed_before,
+            keep
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Deletes the artists with the given ids, along with their albums and tracks.
+    /// Returns the number of artists deleted.
+    pub async fn delete_ids(conn: &mut PgConnection, ids: &[i64]) -> Result<u64, sqlx::Error> {
+        Ok(sqlx::query!("delete from artists where id = any($1)", ids)
+            .execute(conn)
+            .await?
+            .rows_affected())
     }
 }
 
+/// An artist that hasn't been accessed in a while, see [`ArtistInfo::unused`].
+#[derive(Debug, Clone)]
+pub struct UnusedArtist {
+    pub id: i64,
+    pub title: String,
+    pub last_accessed_at: DateTime<Utc>,
+}
+
 /// Represents info about an album, corresponding with the `albums` table in the database.
 #[derive(Debug, Clone)]
 pub struct AlbumInfo {
@@ -69,32 +181,112 @@ pub struct AlbumInfo {
     pub artist: i64,
     pub title: String,
     pub cover_url: String,
+    pub release_date: Option<NaiveDate>,
 }
 
 impl AlbumInfo {
-    /// Creates an `AlbumInfo` from an [`Album`] with the given `artist_id`.
-    pub fn from_album(value: Album, artist_id: u32) -> Self {
-        Self {
-            id: value.id.into(),
-            artist: artist_id.into(),
-            title: value.title,
-            cover_url: value.cover_medium.to_string(),
-        }
-    }
-
     /// Inserts `self` into the database.
     pub async fn insert(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
         sqlx::query!(
-            "insert into albums (id, artist, title, cover_url) values ($1, $2, $3, $4)",
+            "insert into albums (id, artist, title, cover_url, release_date)
+                values ($1, $2, $3, $4, $5)",
             self.id,
             self.artist,
             self.title,
-            self.cover_url
+            self.cover_url,
+            self.release_date
         )
         .execute(conn)
         .await?;
         Ok(())
     }
+
+    /// Inserts `self` into the database, or updates it if it has changed. Only kept
+    /// as the baseline for `benches/cache_writes.rs`; the cache uses [`AlbumInfo::upsert_many`].
+    pub async fn upsert(&self, conn: &mut PgConnection) -> Result<Upsert, sqlx::Error> {
+        let inserted = sqlx::query_scalar!(
+            r#"insert into albums (id, artist, title, cover_url, release_date)
+               values ($1, $2, $3, $4, $5)
+               on conflict (id) do update set
+                   artist = excluded.artist,
+                   title = excluded.title,
+                   cover_url = excluded.cover_url,
+                   release_date = excluded.release_date
+               where (albums.artist, albums.title, albums.cover_url, albums.release_date)
+                   is distinct from
+                   (excluded.artist, excluded.title, excluded.cover_url, excluded.release_date)
+               returning (xmax = 0) as "inserted!""#,
+            self.id,
+            self.artist,
+            self.title,
+            self.cover_url,
+            self.release_date
+        )
+        .fetch_optional(conn)
+        .await?;
+        Ok(Upsert::from_returned(inserted))
+    }
+
+    /// Like [`AlbumInfo::upsert`], but for many albums at once, in a single
+    /// statement. Returns what happened to each album that changed; if several
+    /// albums have the same id, the last one wins.
+    pub async fn upsert_many(
+        conn: &mut PgConnection,
+        albums: &[AlbumInfo],
+    ) -> Result<Vec<Upsert>, sqlx::Error> {
+        // a row can't be updated twice in one statement, so only the last of each
+        // id is kept, without changing the order
+        let mut seen = HashSet::new();
+        let mut albums: Vec<&AlbumInfo> =
+            albums.iter().rev().filter(|a| seen.insert(a.id)).collect();
+        albums.reverse();
+        let inserted = sqlx::query_scalar!(
+            r#"insert into albums (id, artist, title, cover_url, release_date)
+               select * from unnest(
+                   $1::bigint[], $2::bigint[], $3::text[], $4::text[], $5::date[]
+               )
+               on conflict (id) do update set
+                   artist = excluded.artist,
+                   title = excluded.title,
+                   cover_url = excluded.cover_url,
+                   release_date = excluded.release_date
+               where (albums.artist, albums.title, albums.cover_url, albums.release_date)
+                   is distinct from
+                   (excluded.artist, excluded.title, excluded.cover_url, excluded.release_date)
+               returning (xmax = 0) as "inserted!""#,
+            &albums.iter().map(|a| a.id).collect::<Vec<_>>(),
+            &albums.iter().map(|a| a.artist).collect::<Vec<_>>(),
+            &albums.iter().map(|a| a.title.clone()).collect::<Vec<_>>(),
+            &albums
+                .iter()
+                .map(|a| a.cover_url.clone())
+                .collect::<Vec<_>>(),
+            &albums.iter().map(|a| a.release_date).collect::<Vec<_>>() as &[Option<NaiveDate>]
+        )
+        .fetch_all(conn)
+        .await?;
+        Ok(inserted
+            .into_iter()
+            .map(|i| Upsert::from_returned(Some(i)))
+            .collect())
+    }
+
+    /// Deletes the albums of the artist with id `artist` whose ids aren't in `keep`,
+    /// along with their tracks. Returns the number of albums deleted.
+    pub async fn delete_missing(
+        conn: &mut PgConnection,
+        artist: i64,
+        keep: &[i64],
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            "delete from albums where artist = $1 and not (id = any($2))",
+            artist,
+            keep
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected())
+    }
 }
 
 /// Represents info about a track. This corresponds with the `tracks` table, partially joined with `albums`.
@@ -104,37 +296,215 @@ pub struct TrackInfo {
     pub album_title: String,
     pub album_cover_url: String,
     pub album_id: i64,
+    pub album_release_date: Option<NaiveDate>,
     pub title: String,
     pub preview_url: String,
+    /// The length of the full track, in seconds.
+    pub duration: Option<i32>,
+    /// How popular the track is, higher being more popular. Only comparable
+    /// between tracks from the same source.
+    pub rank: Option<i32>,
+    pub explicit: Option<bool>,
+    /// The position of the track on its disc, starting from 1.
+    pub track_position: Option<i32>,
+    pub disc_number: Option<i32>,
+    pub isrc: Option<String>,
 }
 
 impl TrackInfo {
-    /// Creates a `TrackInfo` from a [`Track`] and an [`Album`]
-    pub fn from_deezer(track: Track, album: Album) -> Self {
+    /// A track on album 1 with nothing but an id and a title, for tests.
+    #[cfg(test)]
+    pub(crate) fn for_test(id: i64, title: &str) -> Self {
         Self {
-            id: track.id.into(),
-            album_title: album.title,
-            album_cover_url: album.cover_medium.to_string(),
-            album_id: album.id.into(),
-            title: track.title,
-            preview_url: track.preview.to_string(),
+            id,
+            album_title: "Album".to_owned(),
+            album_cover_url: String::new(),
+            album_id: 1,
+            album_release_date: None,
+            title: title.to_owned(),
+            preview_url: String::new(),
+            duration: None,
+            rank: None,
+            explicit: None,
+            track_position: None,
+            disc_number: None,
+            isrc: None,
         }
     }
 
     /// Inserts `self` into the database.
     pub async fn insert(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
         sqlx::query!(
-            "insert into tracks (id, album, title, preview_url) values ($1, $2, $3, $4)",
+            "insert into tracks
+                (id, album, title, preview_url, duration, rank, explicit, track_position, disc_number, isrc)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
             self.id,
             self.album_id,
             self.title,
-            self.preview_url
+            self.preview_url,
+            self.duration,
+            self.rank,
+            self.explicit,
+            self.track_position,
+            self.disc_number,
+            self.isrc
         )
         .execute(conn)
         .await?;
         Ok(())
     }
 
+    /// Inserts `self` into the database, or updates it if it has changed. Only kept
+    /// as the baseline for `benches/cache_writes.rs`; the cache uses [`TrackInfo::upsert_many`].
+    pub async fn upsert(&self, conn: &mut PgConnection) -> Result<Upsert, sqlx::Error> {
+        let inserted = sqlx::query_scalar!(
+            r#"insert into tracks
+               (id, album, title, preview_url, duration, rank, explicit, track_position, disc_number, isrc)
+               values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+               on conflict (id) do update set
+                   album = excluded.album,
+                   title = excluded.title,
+                   preview_url = excluded.preview_url,
+                   duration = excluded.duration,
+                   rank = excluded.rank,
+                   explicit = excluded.explicit,
+                   track_position = excluded.track_position,
+                   disc_number = excluded.disc_number,
+                   isrc = excluded.isrc
+               where (
+                   tracks.album, tracks.title, tracks.preview_url, tracks.duration, tracks.rank,
+                   tracks.explicit, tracks.track_position, tracks.disc_number, tracks.isrc
+               ) is distinct from (
+                   excluded.album, excluded.title, excluded.preview_url, excluded.duration,
+                   excluded.rank, excluded.explicit, excluded.track_position,
+                   excluded.disc_number, excluded.isrc
+               )
+               returning (xmax = 0) as "inserted!""#,
+            self.id,
+            self.album_id,
+            self.title,
+            self.preview_url,
+            self.duration,
+            self.rank,
+            self.explicit,
+            self.track_position,
+            self.disc_number,
+            self.isrc
+        )
+        .fetch_optional(conn)
+        .await?;
+        Ok(Upsert::from_returned(inserted))
+    }
+
+    /// Like [`TrackInfo::upsert`], but for many tracks at once, in a single
+    /// statement. Returns what happened to each track that changed; if several
+    /// tracks have the same id, the last one wins.
+    pub async fn upsert_many(
+        conn: &mut PgConnection,
+        tracks: &[TrackInfo],
+    ) -> Result<Vec<Upsert>, sqlx::Error> {
+        // a row can't be updated twice in one statement, so only the last of each
+        // id is kept, without changing the order
+        let mut seen = HashSet::new();
+        let mut tracks: Vec<&TrackInfo> =
+            tracks.iter().rev().filter(|t| seen.insert(t.id)).collect();
+        tracks.reverse();
+        let inserted = sqlx::query_scalar!(
+            r#"insert into tracks
+               (id, album, title, preview_url, duration, rank, explicit, track_position, disc_number, isrc)
+               select * from unnest(
+                   $1::bigint[], $2::bigint[], $3::text[], $4::text[], $5::integer[],
+                   $6::integer[], $7::boolean[], $8::integer[], $9::integer[], $10::text[]
+               )
+               on conflict (id) do update set
+                   album = excluded.album,
+                   title = excluded.title,
+                   preview_url = excluded.preview_url,
+                   duration = excluded.duration,
+                   rank = excluded.rank,
+                   explicit = excluded.explicit,
+                   track_position = excluded.track_position,
+                   disc_number = excluded.disc_number,
+                   isrc = excluded.isrc
+               where (
+                   tracks.album, tracks.title, tracks.preview_url, tracks.duration, tracks.rank,
+                   tracks.explicit, tracks.track_position, tracks.disc_number, tracks.isrc
+               ) is distinct from (
+                   excluded.album, excluded.title, excluded.preview_url, excluded.duration,
+                   excluded.rank, excluded.explicit, excluded.track_position,
+                   excluded.disc_number, excluded.isrc
+               )
+               returning (xmax = 0) as "inserted!""#,
+            &tracks.iter().map(|t| t.id).collect::<Vec<_>>(),
+            &tracks.iter().map(|t| t.album_id).collect::<Vec<_>>(),
+            &tracks.iter().map(|t| t.title.clone()).collect::<Vec<_>>(),
+            &tracks
+                .iter()
+                .map(|t| t.preview_url.clone())
+                .collect::<Vec<_>>(),
+            &tracks.iter().map(|t| t.duration).collect::<Vec<_>>() as &[Option<i32>],
+            &tracks.iter().map(|t| t.rank).collect::<Vec<_>>() as &[Option<i32>],
+            &tracks.iter().map(|t| t.explicit).collect::<Vec<_>>() as &[Option<bool>],
+            &tracks.iter().map(|t| t.track_position).collect::<Vec<_>>() as &[Option<i32>],
+            &tracks.iter().map(|t| t.disc_number).collect::<Vec<_>>() as &[Option<i32>],
+            &tracks.iter().map(|t| t.isrc.clone()).collect::<Vec<_>>() as &[Option<String>]
+        )
+        .fetch_all(conn)
+        .await?;
+        Ok(inserted
+            .into_iter()
+            .map(|i| Upsert::from_returned(Some(i)))
+            .collect())
+    }
+
+    /// Deletes the tracks on any of the albums with ids in `albums` whose ids
+    /// aren't in `keep`. Returns the number of tracks deleted.
+    pub async fn delete_missing_from_albums(
+        conn: &mut PgConnection,
+        albums: &[i64],
+        keep: &[i64],
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            "delete from tracks where album = any($1) and not (id = any($2))",
+            albums,
+            keep
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Retrieves the track with id `id` from the database.
+    pub async fn get_from_id(
+        conn: &mut PgConnection,
+        id: i64,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            TrackInfo,
+            "select
+                                        tracks.id as id,
+                                        albums.title as album_title,
+                                        albums.cover_url as album_cover_url,
+                                        albums.id as album_id,
+                                        albums.release_date as album_release_date,
+                                        tracks.title as title,
+                                        tracks.preview_url as preview_url,
+                                        tracks.duration as duration,
+                                        tracks.rank as rank,
+                                        tracks.explicit as explicit,
+                                        tracks.track_position as track_position,
+                                        tracks.disc_number as disc_number,
+                                        tracks.isrc as isrc
+                                    from
+                                        albums join tracks ON albums.id = tracks.album
+                                    where
+                                        tracks.id = $1",
+            id
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
     /// Retrieves all tracks from the artist with id `artist_id` from the database.
     pub async fn from_artist_id(
         conn: &mut PgConnection,
@@ -147,8 +517,15 @@ impl TrackInfo {
                                         albums.title as album_title,
                                         albums.cover_url as album_cover_url,
                                         albums.id as album_id,
+                                        albums.release_date as album_release_date,
                                         tracks.title as title,
-                                        tracks.preview_url as preview_url
+                                        tracks.preview_url as preview_url,
+                                        tracks.duration as duration,
+                                        tracks.rank as rank,
+                                        tracks.explicit as explicit,
+                                        tracks.track_position as track_position,
+                                        tracks.disc_number as disc_number,
+                                        tracks.isrc as isrc
                                     from
                                         albums join tracks ON albums.id = tracks.album
                                     where
@@ -160,6 +537,338 @@ impl TrackInfo {
     }
 }
 
+/// Represents a quiz session, corresponding with the `quiz_sessions` table in the database.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuizSessionInfo {
+    pub id: String,
+    pub artist: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub finished: bool,
+    pub player_name: Option<String>,
+    pub submitted_at: Option<DateTime<Utc>>,
+    pub difficulty: Difficulty,
+    pub distractors: DistractorStrategy,
+}
+
+impl QuizSessionInfo {
+    /// Creates a new, unfinished `QuizSessionInfo` for the artist with id `artist`,
+    /// with the default difficulty and distractors.
+    pub fn new(id: String, artist: u32) -> Self {
+        let now = Utc::now();
+        Self {
+            id,
+            artist: artist.into(),
+            created_at: now,
+            updated_at: now,
+            finished: false,
+            player_name: None,
+            submitted_at: None,
+            difficulty: Difficulty::default(),
+            distractors: DistractorStrategy::default(),
+        }
+    }
+
+    /// Gets a `QuizSessionInfo` corresponding to `id` from the database.
+    pub async fn get_from_id(
+        conn: &mut PgConnection,
+        id: &str,
+    ) -> Result<Option<QuizSessionInfo>, sqlx::Error> {
+        sqlx::query_as!(
+            QuizSessionInfo,
+            r#"select id, artist, created_at, updated_at, finished, player_name, submitted_at,
+                    difficulty as "difficulty: Difficulty",
+                    distractors as "distractors: DistractorStrategy"
+                from quiz_sessions where id = $1"#,
+            id
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
+    /// Inserts `self` into the database.
+    pub async fn insert(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "insert into quiz_sessions
+                (id, artist, created_at, updated_at, finished, player_name, submitted_at, difficulty, distractors)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            self.id,
+            self.artist,
+            self.created_at,
+            self.updated_at,
+            self.finished,
+            self.player_name,
+            self.submitted_at,
+            self.difficulty as Difficulty,
+            self.distractors as DistractorStrategy
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Bumps `updated_at` of the session with id `id`, marking it as finished if `finished` is set.
+    pub async fn update(
+        conn: &mut PgConnection,
+        id: &str,
+        finished: bool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "update quiz_sessions set updated_at = $2, finished = finished or $3 where id = $1",
+            id,
+            Utc::now(),
+            finished
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Submits `name` as the player name of the finished session with id `id` to the
+    /// leaderboard. Returns `false` if a name has already been submitted.
+    pub async fn submit_name(
+        conn: &mut PgConnection,
+        id: &str,
+        name: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query!(
+            "update quiz_sessions set player_name = $2, submitted_at = $3
+                where id = $1 and finished and player_name is null",
+            id,
+            name,
+            Utc::now()
+        )
+        .execute(conn)
+        .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// Computes the score of the session with id `id`.
+    pub async fn score(conn: &mut PgConnection, id: &str) -> Result<SessionScore, sqlx::Error> {
+        sqlx::query_as!(
+            SessionScore,
+            r#"select
+                count(*) filter (where correct) as "score!",
+                count(chosen) as "answered!",
+                count(*) as "total!"
+            from quiz_answers where session = $1"#,
+            id
+        )
+        .fetch_one(conn)
+        .await
+    }
+}
+
+/// The score of a quiz session.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SessionScore {
+    pub score: i64,
+    pub answered: i64,
+    pub total: i64,
+}
+
+/// An entry in the leaderboard of an artist.
+#[derive(Debug, Clone, Serialize)]
+pub struct LeaderboardEntry {
+    pub player_name: String,
+    pub score: i64,
+    pub answered: i64,
+    pub percent_correct: f64,
+    pub time_ms: i64,
+    pub submitted_at: DateTime<Utc>,
+}
+
+impl LeaderboardEntry {
+    /// Retrieves the best `limit` submitted sessions on `difficulty` and with
+    /// `distractors` for the artist with id `artist_id`, ranked by score, then
+    /// percentage of correct answers, then time taken.
+    pub async fn for_artist(
+        conn: &mut PgConnection,
+        artist_id: u32,
+        difficulty: Difficulty,
+        distractors: DistractorStrategy,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"select
+                quiz_sessions.player_name as "player_name!",
+                quiz_sessions.submitted_at as "submitted_at!",
+                count(*) filter (where quiz_answers.correct) as "score!",
+                count(quiz_answers.chosen) as "answered!",
+                coalesce(sum(quiz_answers.response_ms), 0) as "time_ms!"
+            from
+                quiz_sessions join quiz_answers on quiz_sessions.id = quiz_answers.session
+            where
+                quiz_sessions.artist = $1
+                and quiz_sessions.player_name is not null
+                and quiz_sessions.difficulty = $3
+                and quiz_sessions.distractors = $4
+            group by
+                quiz_sessions.id
+            order by
+                3 desc,
+                count(*) filter (where quiz_answers.correct)::float8
+                    / greatest(count(quiz_answers.chosen), 1) desc,
+                5 asc
+            limit $2"#,
+            i64::from(artist_id),
+            limit,
+            difficulty as Difficulty,
+            distractors as DistractorStrategy
+        )
+        .fetch_all(conn)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| LeaderboardEntry {
+                percent_correct: if row.answered == 0 {
+                    0.0
+                } else {
+                    row.score as f64 * 100.0 / row.answered as f64
+                },
+                player_name: row.player_name,
+                score: row.score,
+                answered: row.answered,
+                time_ms: row.time_ms,
+                submitted_at: row.submitted_at,
+            })
+            .collect())
+    }
+
+    /// The time taken to answer the questions, in seconds.
+    pub fn time_secs(&self) -> f64 {
+        self.time_ms as f64 / 1000.0
+    }
+}
+
+/// Represents a question in a quiz session and the answer given to it, corresponding
+/// with the `quiz_answers` table in the database.
+#[derive(Debug, Clone)]
+pub struct QuizAnswerInfo {
+    pub session: String,
+    pub position: i32,
+    pub token: String,
+    pub track: i64,
+    pub answer: String,
+    pub options: Vec<String>,
+    pub chosen: Option<String>,
+    pub correct: Option<bool>,
+    pub asked_at: Option<DateTime<Utc>>,
+    pub answered_at: Option<DateTime<Utc>>,
+    pub response_ms: Option<i32>,
+}
+
+impl QuizAnswerInfo {
+    /// Inserts `self` into the database.
+    pub async fn insert(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "insert into quiz_answers
+                (session, position, token, track, answer, options, chosen, correct, asked_at, answered_at, response_ms)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            self.session,
+            self.position,
+            self.token,
+            self.track,
+            self.answer,
+            &self.options,
+            self.chosen,
+            self.correct,
+            self.asked_at,
+            self.answered_at,
+            self.response_ms
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Gets the question identified by `token` in the session with id `session` from the database.
+    pub async fn get(
+        conn: &mut PgConnection,
+        session: &str,
+        token: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            QuizAnswerInfo,
+            "select session, position, token, track, answer, options, chosen, correct, asked_at, answered_at, response_ms
+                from quiz_answers where session = $1 and token = $2",
+            session,
+            token
+        )
+        .fetch_optional(conn)
+        .await
+    }
+
+    /// Retrieves all questions of the session with id `session`, in order.
+    pub async fn from_session(
+        conn: &mut PgConnection,
+        session: &str,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            QuizAnswerInfo,
+            "select session, position, token, track, answer, options, chosen, correct, asked_at, answered_at, response_ms
+                from quiz_answers where session = $1 order by position",
+            session
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    /// Records the time the question was first asked.
+    pub async fn mark_asked(&mut self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+        if self.asked_at.is_some() {
+            return Ok(());
+        }
+        let now = Utc::now();
+        sqlx::query!(
+            "update quiz_answers set asked_at = $3 where session = $1 and token = $2 and asked_at is null",
+            self.session,
+            self.token,
+            now
+        )
+        .execute(conn)
+        .await?;
+        self.asked_at = Some(now);
+        Ok(())
+    }
+
+    /// Records `chosen` as the answer to this question. Returns `false` if the question
+    /// had already been answered.
+    pub async fn record_answer(
+        &mut self,
+        conn: &mut PgConnection,
+        chosen: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let now = Utc::now();
+        let correct = self.answer == chosen;
+        let response_ms = self
+            .asked_at
+            .and_then(|asked| (now - asked).num_milliseconds().try_into().ok());
+        let res = sqlx::query!(
+            "update quiz_answers set chosen = $3, correct = $4, answered_at = $5, response_ms = $6
+                where session = $1 and token = $2 and chosen is null",
+            self.session,
+            self.token,
+            chosen,
+            correct,
+            now,
+            response_ms.as_ref()
+        )
+        .execute(conn)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.chosen = Some(chosen.to_owned());
+        self.correct = Some(correct);
+        self.answered_at = Some(now);
+        self.response_ms = response_ms;
+        Ok(true)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -190,4 +899,109 @@ mod test {
         );
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn test_upsert_many(mut conn: PoolConnection<Postgres>) -> Result<(), sqlx::Error> {
+        ArtistInfo {
+            id: 1,
+            title: "Artist".to_owned(),
+            icon_url: String::new(),
+            updated_at: Utc::now(),
+        }
+        .insert(&mut conn)
+        .await?;
+        let album = AlbumInfo {
+            id: 10,
+            artist: 1,
+            title: "Album".to_owned(),
+            cover_url: String::new(),
+            release_date: None,
+        };
+        assert_eq!(
+            AlbumInfo::upsert_many(&mut conn, &[album.clone(), album.clone()]).await?,
+            [Upsert::Inserted]
+        );
+
+        let track = |id: i64, title: &str| TrackInfo {
+            album_id: album.id,
+            ..TrackInfo::for_test(id, title)
+        };
+        let tracks = [track(100, "One"), track(101, "Two")];
+        assert_eq!(
+            TrackInfo::upsert_many(&mut conn, &tracks).await?,
+            [Upsert::Inserted, Upsert::Inserted]
+        );
+
+        // only changed tracks come back, and the last of duplicates wins
+        let tracks = [
+            track(100, "One"),
+            track(101, "Two"),
+            track(101, "Two (Live)"),
+            track(102, "Three"),
+        ];
+        let upserts = TrackInfo::upsert_many(&mut conn, &tracks).await?;
+        assert_eq!(upserts.len(), 2);
+        assert!(upserts.contains(&Upsert::Inserted) && upserts.contains(&Upsert::Updated));
+        assert_eq!(
+            TrackInfo::get_from_id(&mut conn, 101).await?.unwrap().title,
+            "Two (Live)"
+        );
+
+        assert_eq!(
+            TrackInfo::delete_missing_from_albums(&mut conn, &[10], &[100]).await?,
+            2
+        );
+        assert!(TrackInfo::get_from_id(&mut conn, 100).await?.is_some());
+        assert!(TrackInfo::get_from_id(&mut conn, 102).await?.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_track_metadata(mut conn: PoolConnection<Postgres>) -> Result<(), sqlx::Error> {
+        ArtistInfo {
+            id: 1,
+            title: "Artist".to_owned(),
+            icon_url: String::new(),
+            updated_at: Utc::now(),
+        }
+        .insert(&mut conn)
+        .await?;
+        let album = AlbumInfo {
+            id: 10,
+            artist: 1,
+            title: "Album".to_owned(),
+            cover_url: String::new(),
+            release_date: NaiveDate::from_ymd_opt(2016, 10, 12),
+        };
+        album.insert(&mut conn).await?;
+        let mut track = TrackInfo {
+            album_id: album.id,
+            album_release_date: album.release_date,
+            duration: Some(213),
+            rank: Some(401233),
+            explicit: Some(false),
+            track_position: Some(1),
+            disc_number: Some(1),
+            isrc: Some("XX0000000001".to_owned()),
+            ..TrackInfo::for_test(100, "Track")
+        };
+        assert_eq!(track.upsert(&mut conn).await?, Upsert::Inserted);
+
+        let stored = TrackInfo::get_from_id(&mut conn, 100).await?.unwrap();
+        assert_eq!(stored.album_release_date, album.release_date);
+        assert_eq!(stored.duration, Some(213));
+        assert_eq!(stored.rank, Some(401233));
+        assert_eq!(stored.explicit, Some(false));
+        assert_eq!(stored.track_position, Some(1));
+        assert_eq!(stored.disc_number, Some(1));
+        assert_eq!(stored.isrc.as_deref(), Some("XX0000000001"));
+
+        // a change in metadata alone counts as an update
+        track.rank = Some(500000);
+        assert_eq!(
+            TrackInfo::upsert_many(&mut conn, &[track]).await?,
+            [Upsert::Updated]
+        );
+        Ok(())
+    }
 }
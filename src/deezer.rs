@@ -1,9 +1,11 @@
+use std::io;
 use std::iter;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
 
-use chrono::NaiveDate;
-use lazy_static::lazy_static;
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use futures::stream::{self, Stream, TryStreamExt};
 use reqwest::Method;
 use reqwest::Request;
 use reqwest::Response;
@@ -11,14 +13,53 @@ use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_repr::Deserialize_repr;
 use thiserror::Error;
+use tokio::select;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
-use tokio::task::JoinSet;
-use tokio::time::Instant;
 use url::Url;
 
-lazy_static! {
-    static ref DEEZER_API_BASE: Url = Url::parse("https://api.deezer.com").unwrap();
+use crate::db::{AlbumInfo, ArtistInfo, TrackInfo};
+use crate::source::{self, MusicSource, Priority};
+
+pub mod fixtures;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod ratelimit;
+pub mod retry;
+
+use fixtures::{FixtureMode, Fixtures};
+use ratelimit::{RateLimitConfig, TokenBucket};
+use retry::RetryConfig;
+
+const DEFAULT_API_BASE: &str = "https://api.deezer.com";
+
+/// The number of items requested per page when paging through a whole listing.
+const PAGE_SIZE: u32 = 100;
+
+/// Configures the Deezer client.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DeezerConfig {
+    /// The base URL of the API. Mostly useful for pointing the quiz at a mock
+    /// or caching proxy.
+    pub api_base: Url,
+    /// Records responses to, or replays them from, a fixtures directory.
+    pub fixtures: Option<Fixtures>,
+    /// How requests that failed for transient reasons are retried.
+    pub retry: RetryConfig,
+    /// How quickly requests may be sent.
+    pub rate_limit: RateLimitConfig,
+}
+
+impl Default for DeezerConfig {
+    fn default() -> Self {
+        Self {
+            api_base: Url::parse(DEFAULT_API_BASE).unwrap(),
+            fixtures: None,
+            retry: RetryConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -29,6 +70,26 @@ pub enum Error {
     UrlParseError(#[from] url::ParseError),
     #[error("api error")]
     ApiError(#[from] ApiErrCode),
+    #[error("response parse error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("fixture error")]
+    FixtureError(#[from] io::Error),
+    #[error("the client has shut down")]
+    Closed,
+}
+
+impl Error {
+    /// Whether the request that caused `self` might succeed if tried again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::ReqwestError(e) => match e.status() {
+                Some(status) => status.is_server_error() || status.as_u16() == 429,
+                None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            },
+            Error::ApiError(code) => matches!(code, ApiErrCode::Quota | ApiErrCode::ServiceBusy),
+            _ => false,
+        }
+    }
 }
 
 /// Represents an artist as returned from the Deezer API.
@@ -73,43 +134,92 @@ pub struct Track {
     pub rank: u32,
     pub explicit_lyrics: bool,
     pub preview: Url,
+    /// Only included when listing an album's tracks or getting a single track.
+    #[serde(default)]
+    pub track_position: Option<u32>,
+    #[serde(default)]
+    pub disk_number: Option<u32>,
+    #[serde(default)]
+    pub isrc: Option<String>,
 }
 
 /// Represents a paginated response as returned from the Deezer API.
 #[derive(Clone, Debug, Deserialize)]
 pub struct PaginatedResponse<T> {
     pub data: Vec<T>,
+    #[serde(default)]
     pub total: u32,
+    /// The URL of the next page, if there is one.
+    #[serde(default)]
+    pub next: Option<Url>,
 }
 
 struct DeezerRequest {
     req: Request,
+    priority: Priority,
     tx: oneshot::Sender<Result<reqwest::Response, reqwest::Error>>,
 }
 
 /// Represents the Deezer client. Requests are properly ratelimited.
 #[derive(Clone)]
 pub struct Deezer {
-    tx: mpsc::Sender<DeezerRequest>,
+    interactive_tx: mpsc::Sender<DeezerRequest>,
+    background_tx: mpsc::Sender<DeezerRequest>,
+    base: Url,
+    fixtures: Option<Arc<Fixtures>>,
+    retry: RetryConfig,
+    queued: Arc<AtomicUsize>,
+}
+
+/// Counts a request as queued until it's handed over to [`serve_deezer`], which
+/// stops counting it once it's sent.
+struct QueuedGuard<'a> {
+    queued: &'a AtomicUsize,
+}
+
+impl<'a> QueuedGuard<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Self { queued }
+    }
+
+    fn handed_over(self) {
+        std::mem::forget(self);
+    }
 }
 
-async fn serve_deezer(mut rx: mpsc::Receiver<DeezerRequest>) {
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn serve_deezer(
+    mut interactive_rx: mpsc::Receiver<DeezerRequest>,
+    mut background_rx: mpsc::Receiver<DeezerRequest>,
+    mut bucket: TokenBucket,
+    queued: Arc<AtomicUsize>,
+) {
     let c = Arc::new(reqwest::Client::new());
     loop {
-        let t = Instant::now();
-        let mut set = JoinSet::new();
-
-        for _ in 0..45 {
-            let Some(r) = rx.recv().await else { return };
-            let cref = Arc::clone(&c);
-            set.spawn(async move {
-                r.tx.send(cref.execute(r.req).await)
-                    .expect("oneshot channel closed");
-            });
-        }
-
-        while set.join_next().await.is_some() {}
-        tokio::time::sleep_until(t + Duration::from_secs(5)).await;
+        // only pick a request once it can be sent, so that interactive requests
+        // arriving in the meantime aren't stuck behind it
+        bucket.ready().await;
+        let r = select! {
+            biased;
+            Some(r) = interactive_rx.recv() => r,
+            Some(r) = background_rx.recv() => r,
+            else => return,
+        };
+        bucket.acquire().await;
+        queued.fetch_sub(1, Ordering::Relaxed);
+        log::debug!("sending {:?} request {}", r.priority, r.req.url());
+
+        let cref = Arc::clone(&c);
+        tokio::spawn(async move {
+            // the requester may have given up waiting, which is fine
+            _ = r.tx.send(cref.execute(r.req).await);
+        });
     }
 }
 
@@ -155,20 +265,84 @@ enum DeezerResponse<T> {
 
 impl Deezer {
     pub fn new() -> Self {
-        let (tx, rx) = mpsc::channel(45);
-        tokio::spawn(serve_deezer(rx));
-        Self { tx }
+        Self::from_config(&DeezerConfig::default())
+    }
+
+    pub fn from_config(config: &DeezerConfig) -> Self {
+        let deezer = Self::spawn(config.api_base.clone(), &config.rate_limit)
+            .with_retry(config.retry.clone());
+        match &config.fixtures {
+            Some(fixtures) => deezer.with_fixtures(fixtures.clone()),
+            None => deezer,
+        }
+    }
+
+    /// Creates a client for the API at `base` instead of the real Deezer API.
+    pub fn with_base_url(base: Url) -> Self {
+        Self::spawn(base, &RateLimitConfig::default())
+    }
+
+    fn spawn(base: Url, rate_limit: &RateLimitConfig) -> Self {
+        let capacity = rate_limit.requests.max(1) as usize;
+        let (interactive_tx, interactive_rx) = mpsc::channel(capacity);
+        let (background_tx, background_rx) = mpsc::channel(capacity);
+        let queued = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(serve_deezer(
+            interactive_rx,
+            background_rx,
+            TokenBucket::new(rate_limit),
+            Arc::clone(&queued),
+        ));
+        Self {
+            interactive_tx,
+            background_tx,
+            base,
+            fixtures: None,
+            retry: RetryConfig::default(),
+            queued,
+        }
+    }
+
+    /// Gets the number of requests waiting to be sent because of the rate limit.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
     }
 
-    async fn send_rq(&self, req: Request) -> Result<Response, Error> {
+    /// Makes the client retry failed requests according to `retry`.
+    pub fn with_retry(self, retry: RetryConfig) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Makes the client record its responses to, or replay them from, `fixtures`.
+    pub fn with_fixtures(self, fixtures: Fixtures) -> Self {
+        Self {
+            fixtures: Some(Arc::new(fixtures)),
+            ..self
+        }
+    }
+
+    async fn send_rq(&self, req: Request, priority: Priority) -> Result<Response, Error> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(DeezerRequest { req, tx }).await.unwrap();
-        rx.await
-            .expect("oneshot channel closed")
-            .map_err(Into::into)
+        let lane = match priority {
+            Priority::Interactive => &self.interactive_tx,
+            Priority::Background => &self.background_tx,
+        };
+        // counted while waiting for room in the lane too, but only until the
+        // request is handed over if this is cancelled or fails in the meantime
+        let queued = QueuedGuard::new(&self.queued);
+        lane.send(DeezerRequest { req, priority, tx })
+            .await
+            .map_err(|_| Error::Closed)?;
+        queued.handed_over();
+        rx.await.map_err(|_| Error::Closed)?.map_err(Into::into)
     }
 
-    async fn get<'a, T, PathIt, It, K, V>(&self, path: PathIt, params: It) -> Result<T, Error>
+    async fn get<'a, T, PathIt, It, K, V>(
+        &self,
+        priority: Priority,
+        path: PathIt,
+        params: It,
+    ) -> Result<T, Error>
     where
         T: DeserializeOwned,
         PathIt: IntoIterator<Item = &'a str>,
@@ -176,11 +350,62 @@ impl Deezer {
         K: AsRef<str>,
         V: AsRef<str>,
     {
-        let mut url = DEEZER_API_BASE.clone();
-        url.path_segments_mut().unwrap().extend(path);
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("API base URL can't be a base")
+            .pop_if_empty()
+            .extend(path);
         url.query_pairs_mut().extend_pairs(params);
-        let rq = Request::new(Method::GET, url);
-        let retval: DeezerResponse<T> = self.send_rq(rq).await?.error_for_status()?.json().await?;
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+
+        // fixtures are keyed by the request relative to the API base, so they can
+        // be replayed against any base
+        let request = url
+            .as_str()
+            .strip_prefix(self.base.as_str())
+            .unwrap_or(url.as_str())
+            .trim_start_matches('/')
+            .to_owned();
+
+        let mut attempt = 1;
+        loop {
+            match self.get_once(&url, &request, priority).await {
+                Err(e) if e.is_transient() && attempt < self.retry.attempts => {
+                    let delay = self.retry.delay(attempt, &mut rand::thread_rng());
+                    log::warn!("Deezer request {request} failed ({e}), retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn get_once<T: DeserializeOwned>(
+        &self,
+        url: &Url,
+        request: &str,
+        priority: Priority,
+    ) -> Result<T, Error> {
+        let body = match self.fixtures.as_deref() {
+            Some(fixtures) if fixtures.mode == FixtureMode::Replay => fixtures.load(request)?,
+            fixtures => {
+                let rq = Request::new(Method::GET, url.clone());
+                let body = self
+                    .send_rq(rq, priority)
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+                if let Some(fixtures) = fixtures {
+                    fixtures.save(request, &body)?;
+                }
+                body.to_vec()
+            }
+        };
+        let retval: DeezerResponse<T> = serde_json::from_slice(&body)?;
 
         match retval {
             DeezerResponse::Error {
@@ -198,6 +423,7 @@ impl Deezer {
         limit: u32,
     ) -> Result<PaginatedResponse<Artist>, Error> {
         self.get(
+            Priority::Interactive,
             ["search", "artist"],
             [
                 ("q", q),
@@ -209,9 +435,23 @@ impl Deezer {
     }
 
     /// Gets artist by ID
-    pub async fn artist(&self, id: u32) -> Result<Artist, Error> {
-        self.get(["artist", &id.to_string()], iter::empty::<(&str, &str)>())
-            .await
+    pub async fn artist(&self, id: u32, priority: Priority) -> Result<Artist, Error> {
+        self.get(
+            priority,
+            ["artist", &id.to_string()],
+            iter::empty::<(&str, &str)>(),
+        )
+        .await
+    }
+
+    /// Gets track by ID
+    pub async fn track(&self, id: u32) -> Result<Track, Error> {
+        self.get(
+            Priority::Interactive,
+            ["track", &id.to_string()],
+            iter::empty::<(&str, &str)>(),
+        )
+        .await
     }
 
     /// Gets albums by artist
@@ -222,12 +462,63 @@ impl Deezer {
         limit: u32,
     ) -> Result<PaginatedResponse<Album>, Error> {
         self.get(
+            Priority::Background,
             ["artist", &id.to_string(), "albums"],
             [("index", &index.to_string()), ("limit", &limit.to_string())],
         )
         .await
     }
 
+    /// Gets all albums by artist, fetching as many pages as needed.
+    pub fn artist_albums_all(&self, id: u32) -> impl Stream<Item = Result<Album, Error>> + '_ {
+        self.paginate(
+            ["artist".to_owned(), id.to_string(), "albums".to_owned()],
+            PAGE_SIZE,
+        )
+    }
+
+    /// Gets all tracks in album, fetching as many pages as needed.
+    pub fn album_tracks_all(&self, id: u32) -> impl Stream<Item = Result<Track, Error>> + '_ {
+        self.paginate(
+            ["album".to_owned(), id.to_string(), "tracks".to_owned()],
+            PAGE_SIZE,
+        )
+    }
+
+    /// Pages through the listing at `path`, `page_size` items at a time. Paging
+    /// stops once `total` items have been seen and there is no `next` page, or
+    /// when a page comes back empty.
+    fn paginate<T: DeserializeOwned>(
+        &self,
+        path: [String; 3],
+        page_size: u32,
+    ) -> impl Stream<Item = Result<T, Error>> + '_ {
+        stream::try_unfold(Some(0), move |index| {
+            let path = path.clone();
+            async move {
+                let Some(index) = index else {
+                    return Ok::<_, Error>(None);
+                };
+                let page: PaginatedResponse<T> = self
+                    .get(
+                        Priority::Background,
+                        path.iter().map(String::as_str),
+                        [
+                            ("index", index.to_string()),
+                            ("limit", page_size.to_string()),
+                        ],
+                    )
+                    .await?;
+
+                let next_index = index + page.data.len() as u32;
+                let more = page.next.is_some() || next_index < page.total;
+                let next = (more && !page.data.is_empty()).then_some(next_index);
+                Ok(Some((stream::iter(page.data.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+
     /// Gets tracks in album
     pub async fn album_tracks(
         &self,
@@ -236,6 +527,7 @@ impl Deezer {
         limit: u32,
     ) -> Result<PaginatedResponse<Track>, Error> {
         self.get(
+            Priority::Background,
             ["album", &id.to_string(), "tracks"],
             [("index", &index.to_string()), ("limit", &limit.to_string())],
         )
@@ -249,14 +541,110 @@ impl Default for Deezer {
     }
 }
 
+impl From<Artist> for ArtistInfo {
+    fn from(a: Artist) -> Self {
+        Self {
+            id: a.id.into(),
+            title: a.name,
+            icon_url: a.picture_medium.to_string(),
+            updated_at: Utc::now(),
+        }
+    }
+}
+
+impl Album {
+    /// Converts `self` into an [`AlbumInfo`] with the given `artist_id`.
+    pub fn into_info(self, artist_id: u32) -> AlbumInfo {
+        AlbumInfo {
+            id: self.id.into(),
+            artist: artist_id.into(),
+            title: self.title,
+            cover_url: self.cover_medium.to_string(),
+            release_date: Some(self.release_date),
+        }
+    }
+}
+
+impl Track {
+    /// Converts `self` into a [`TrackInfo`] on `album`.
+    pub fn into_info(self, album: &AlbumInfo) -> TrackInfo {
+        TrackInfo {
+            id: self.id.into(),
+            album_title: album.title.clone(),
+            album_cover_url: album.cover_url.clone(),
+            album_id: album.id,
+            album_release_date: album.release_date,
+            title: self.title,
+            preview_url: self.preview.to_string(),
+            duration: i32::try_from(self.duration).ok(),
+            rank: i32::try_from(self.rank).ok(),
+            explicit: Some(self.explicit_lyrics),
+            track_position: self.track_position.and_then(|p| i32::try_from(p).ok()),
+            disc_number: self.disk_number.and_then(|d| i32::try_from(d).ok()),
+            isrc: self.isrc,
+        }
+    }
+}
+
+#[async_trait]
+impl MusicSource for Deezer {
+    async fn search_artists(
+        &self,
+        q: &str,
+        index: u32,
+        limit: u32,
+    ) -> Result<Vec<ArtistInfo>, source::Error> {
+        let artists = self.search_artist(q, index, limit).await?.data;
+        Ok(artists.into_iter().map(ArtistInfo::from).collect())
+    }
+
+    async fn artist(&self, id: u32, priority: Priority) -> Result<ArtistInfo, source::Error> {
+        Ok(Deezer::artist(self, id, priority).await?.into())
+    }
+
+    async fn artist_albums(&self, id: u32) -> Result<Vec<AlbumInfo>, source::Error> {
+        Ok(self
+            .artist_albums_all(id)
+            .map_ok(|a| a.into_info(id))
+            .try_collect()
+            .await?)
+    }
+
+    async fn album_tracks(&self, album: &AlbumInfo) -> Result<Vec<TrackInfo>, source::Error> {
+        let id = u32::try_from(album.id).map_err(|_| source::Error::NotFound)?;
+        Ok(self
+            .album_tracks_all(id)
+            .map_ok(|t| t.into_info(album))
+            .try_collect()
+            .await?)
+    }
+
+    async fn preview_url(&self, track: i64) -> Result<String, source::Error> {
+        let id = u32::try_from(track).map_err(|_| source::Error::NotFound)?;
+        Ok(self.track(id).await?.preview.into())
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use chrono::TimeDelta;
 
+    use super::mock::{Failure, MockDeezer};
     use super::*;
 
+    /// A retry policy that doesn't slow the tests down.
+    pub(crate) fn fast_retry() -> RetryConfig {
+        RetryConfig {
+            attempts: 3,
+            base_delay: TimeDelta::try_milliseconds(1).unwrap(),
+            max_delay: TimeDelta::try_milliseconds(10).unwrap(),
+        }
+    }
+
     #[tokio::test]
     async fn test_search_artist() -> Result<(), Error> {
-        let deez = Deezer::new();
+        let mock = MockDeezer::start().await;
+        let deez = mock.client();
         let artists = deez.search_artist("Mili", 0, 25).await?;
         assert_eq!(artists.data[0].name, "Mili");
         Ok(())
@@ -264,16 +652,22 @@ mod tests {
 
     #[tokio::test]
     async fn test_artist() -> Result<(), Error> {
-        let deez = Deezer::new();
-        let artist = deez.artist(56563392).await?;
+        let mock = MockDeezer::start().await;
+        let deez = mock.client();
+        let artist = deez.artist(56563392, Priority::Interactive).await?;
         assert_eq!(artist.name, "Mili");
         Ok(())
     }
 
     #[tokio::test]
     async fn test_missing_artist() {
-        let deez = Deezer::new();
-        let artist_err = match deez.artist(909409309).await.unwrap_err() {
+        let mock = MockDeezer::start().await;
+        let deez = mock.client();
+        let artist_err = match deez
+            .artist(909409309, Priority::Interactive)
+            .await
+            .unwrap_err()
+        {
             Error::ApiError(e) => e,
             other => panic!("expected reqwest error, got: {other:#?}"),
         };
@@ -283,7 +677,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_artist_albums() -> Result<(), Error> {
-        let deez = Deezer::new();
+        let mock = MockDeezer::start().await;
+        let deez = mock.client();
         let albums = deez.artist_albums(56563392, 0, 100).await?;
         assert!(albums
             .data
@@ -294,9 +689,226 @@ mod tests {
 
     #[tokio::test]
     async fn test_album_tracks() -> Result<(), Error> {
-        let deez = Deezer::new();
+        let mock = MockDeezer::start().await;
+        let deez = mock.client();
         let tracks = deez.album_tracks(59795132, 0, 100).await?;
         assert!(tracks.data.into_iter().any(|a| a.title == "Summoning 101"));
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_pagination() -> Result<(), Error> {
+        let mock = MockDeezer::start().await;
+        let deez = mock.client();
+        let first = deez.artist_albums(56563392, 0, 2).await?;
+        let rest = deez.artist_albums(56563392, 2, 2).await?;
+        assert_eq!(first.total, 3);
+        assert_eq!(first.data.len(), 2);
+        assert_eq!(rest.data.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_paginate() -> Result<(), Error> {
+        let mock = MockDeezer::start().await;
+        let deez = mock.client();
+        let path = || {
+            [
+                "album".to_owned(),
+                "59795132".to_owned(),
+                "tracks".to_owned(),
+            ]
+        };
+        let paged: Vec<Track> = deez.paginate(path(), 2).try_collect().await?;
+        let single: Vec<Track> = deez.paginate(path(), 100).try_collect().await?;
+        assert_eq!(paged.len(), 5);
+        assert_eq!(
+            paged.iter().map(|t| t.id).collect::<Vec<_>>(),
+            single.iter().map(|t| t.id).collect::<Vec<_>>()
+        );
+
+        let albums: Vec<Album> = deez.artist_albums_all(56563392).try_collect().await?;
+        assert_eq!(albums.len(), 3);
+        assert!(matches!(
+            deez.artist_albums_all(909409309)
+                .try_collect::<Vec<_>>()
+                .await,
+            Err(Error::ApiError(ApiErrCode::DataNotFound))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_record_replay() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let mock = MockDeezer::start().await;
+        let recorder = mock
+            .client()
+            .with_fixtures(Fixtures::new(FixtureMode::Record, dir.path()));
+        let recorded = recorder.album_tracks(59795132, 0, 100).await?;
+        assert!(matches!(
+            recorder.artist(909409309, Priority::Interactive).await,
+            Err(Error::ApiError(ApiErrCode::DataNotFound))
+        ));
+        drop(mock);
+
+        let replayer = Deezer::with_base_url(Url::parse("http://127.0.0.1:9").unwrap())
+            .with_fixtures(Fixtures::new(FixtureMode::Replay, dir.path()));
+        let replayed = replayer.album_tracks(59795132, 0, 100).await?;
+        assert_eq!(
+            recorded.data.iter().map(|t| t.id).collect::<Vec<_>>(),
+            replayed.data.iter().map(|t| t.id).collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            replayer.artist(909409309, Priority::Interactive).await,
+            Err(Error::ApiError(ApiErrCode::DataNotFound))
+        ));
+        assert!(matches!(
+            replayer.artist(56563392, Priority::Interactive).await,
+            Err(Error::FixtureError(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry() -> Result<(), Error> {
+        let mock = MockDeezer::start().await;
+        let deez = mock.client().with_retry(fast_retry());
+
+        mock.fail_next(1, Failure::Quota);
+        mock.fail_next(1, Failure::Unavailable);
+        assert_eq!(
+            deez.artist(56563392, Priority::Interactive).await?.name,
+            "Mili"
+        );
+        assert_eq!(mock.request_count(), 3);
+
+        mock.fail_next(3, Failure::ServiceBusy);
+        assert!(matches!(
+            deez.artist(56563392, Priority::Interactive).await,
+            Err(Error::ApiError(ApiErrCode::ServiceBusy))
+        ));
+        assert_eq!(mock.request_count(), 6);
+
+        // errors that won't go away aren't retried
+        assert!(deez.artist(909409309, Priority::Interactive).await.is_err());
+        assert_eq!(mock.request_count(), 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() -> Result<(), Error> {
+        let mock = MockDeezer::start().await;
+        let deez = Deezer::from_config(&DeezerConfig {
+            api_base: mock.base_url(),
+            rate_limit: RateLimitConfig {
+                requests: 2,
+                per: TimeDelta::try_minutes(1).unwrap(),
+                burst: 2,
+            },
+            ..Default::default()
+        });
+
+        // the first requests go through straight away...
+        let start = std::time::Instant::now();
+        deez.artist(56563392, Priority::Interactive).await?;
+        deez.artist(56563392, Priority::Interactive).await?;
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        assert_eq!(deez.queue_depth(), 0);
+
+        // ...but then the budget is spent
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let deez = deez.clone();
+                tokio::spawn(async move {
+                    deez.artist(56563392, Priority::Interactive)
+                        .await
+                        .map(|_| ())
+                })
+            })
+            .collect();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(deez.queue_depth(), 3);
+        assert_eq!(mock.request_count(), 2);
+
+        // only two fit in the queue, so the third is still waiting to be handed
+        // over, and stops being counted when it's given up on
+        waiting.iter().for_each(|t| t.abort());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(deez.queue_depth(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_priority() -> Result<(), Error> {
+        let mock = MockDeezer::start().await;
+        let deez = Deezer::from_config(&DeezerConfig {
+            api_base: mock.base_url(),
+            rate_limit: RateLimitConfig {
+                requests: 1,
+                per: TimeDelta::try_milliseconds(300).unwrap(),
+                burst: 1,
+            },
+            ..Default::default()
+        });
+        deez.artist(56563392, Priority::Interactive).await?;
+
+        let background: Vec<_> = (0..3)
+            .map(|_| {
+                let deez = deez.clone();
+                tokio::spawn(async move { deez.album_tracks(59795132, 0, 10).await.map(|_| ()) })
+            })
+            .collect();
+        let artist = {
+            let deez = deez.clone();
+            tokio::spawn(async move {
+                MusicSource::artist(&deez, 56563392, Priority::Background)
+                    .await
+                    .map(|_| ())
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        // queued after the crawl, but sent before any of it
+        deez.search_artist("Mili", 0, 10).await?;
+        assert!(background.iter().all(|t| !t.is_finished()));
+        // refreshing an artist in the background is part of the crawl
+        assert!(!artist.is_finished());
+        artist.await.unwrap().unwrap();
+
+        for t in background {
+            t.await.unwrap()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = RetryConfig::default();
+        let mut rng = rand::thread_rng();
+        for (attempt, max_ms) in [(1, 500), (2, 1000), (3, 2000), (10, 10000), (100, 10000)] {
+            let delay = retry.delay(attempt, &mut rng).as_millis();
+            assert!(
+                (max_ms / 2..=max_ms).contains(&delay),
+                "attempt {attempt}: {delay}ms"
+            );
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let config: DeezerConfig =
+            toml::from_str("api_base = \"http://localhost/deezer/\"").unwrap();
+        assert_eq!(config.api_base.path(), "/deezer/");
+        let config: DeezerConfig = toml::from_str("").unwrap();
+        assert_eq!(config.api_base.as_str(), "https://api.deezer.com/");
+        assert_eq!(config.retry.attempts, 4);
+
+        let config: DeezerConfig =
+            toml::from_str("[retry]\nattempts = 2\nbase_delay = \"250ms\"").unwrap();
+        assert_eq!(config.retry.attempts, 2);
+        assert_eq!(config.retry.base_delay.num_milliseconds(), 250);
+        assert_eq!(config.retry.max_delay.num_seconds(), 10);
+        assert_eq!(config.rate_limit.requests, 45);
+    }
 }
@@ -0,0 +1,74 @@
+//! Recording Deezer API responses to disk and replaying them, so that tests can
+//! run against a real catalogue captured once.
+
+use std::{fs, io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Whether [`Fixtures`] are being written or read.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FixtureMode {
+    /// Requests go to the API as usual, and every response is saved.
+    Record,
+    /// Requests never reach the API, and are answered from saved responses.
+    Replay,
+}
+
+/// A directory of recorded responses, one JSON file per request.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Fixtures {
+    pub mode: FixtureMode,
+    pub dir: PathBuf,
+}
+
+/// A single recorded request and its response body.
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    request: String,
+    response: Value,
+}
+
+impl Fixtures {
+    pub fn new(mode: FixtureMode, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            mode,
+            dir: dir.into(),
+        }
+    }
+
+    /// Gets the path of the fixture for `request`, which is the path and query
+    /// of the request relative to the API base, e.g. `artist/1/albums?index=0`.
+    fn path(&self, request: &str) -> PathBuf {
+        let hash = request.bytes().fold(0x811c9dc5u32, |h, b| {
+            (h ^ u32::from(b)).wrapping_mul(0x01000193)
+        });
+        let name: String = request
+            .chars()
+            .take(100)
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+                _ => '_',
+            })
+            .collect();
+        self.dir.join(format!("{name}-{hash:08x}.json"))
+    }
+
+    /// Loads the recorded response body for `request`.
+    pub fn load(&self, request: &str) -> io::Result<Vec<u8>> {
+        let contents = fs::read(self.path(request))?;
+        let fixture: Fixture = serde_json::from_slice(&contents)?;
+        Ok(serde_json::to_vec(&fixture.response)?)
+    }
+
+    /// Saves `body` as the response to `request`.
+    pub fn save(&self, request: &str, body: &[u8]) -> io::Result<()> {
+        let fixture = Fixture {
+            request: request.to_owned(),
+            response: serde_json::from_slice(body)?,
+        };
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(request), serde_json::to_vec_pretty(&fixture)?)
+    }
+}
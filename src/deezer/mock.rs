@@ -0,0 +1,294 @@
+//! An in-process stand-in for the Deezer API, serving a canned catalogue so
+//! tests don't need the network.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use actix_web::{
+    body::{BoxBody, EitherBody},
+    dev::{ServerHandle, ServiceRequest, ServiceResponse},
+    middleware::{from_fn, Next},
+    web::{self, Data, Query},
+    App, HttpRequest, HttpResponse, HttpServer,
+};
+use chrono::TimeDelta;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use url::Url;
+
+use super::{Deezer, DeezerConfig, RateLimitConfig};
+
+/// The catalogue served by [`MockDeezer`]: a list of Deezer artist objects,
+/// each with an extra `albums` field holding album objects, each of which has
+/// an extra `tracks` field holding track objects.
+const CATALOGUE: &str = include_str!("../../fixtures/deezer/catalogue.json");
+
+/// The page size Deezer uses when no `limit` is given.
+const DEFAULT_LIMIT: usize = 25;
+
+struct Catalogue {
+    artists: Vec<Value>,
+}
+
+impl Catalogue {
+    fn artist(&self, id: u64) -> Option<&Value> {
+        self.artists.iter().find(|a| a["id"] == id)
+    }
+
+    fn albums(&self) -> impl Iterator<Item = &Value> {
+        self.artists
+            .iter()
+            .flat_map(|a| a["albums"].as_array().into_iter().flatten())
+    }
+
+    fn album(&self, id: u64) -> Option<&Value> {
+        self.albums().find(|a| a["id"] == id)
+    }
+
+    fn track(&self, id: u64) -> Option<&Value> {
+        self.albums()
+            .flat_map(|a| a["tracks"].as_array().into_iter().flatten())
+            .find(|t| t["id"] == id)
+    }
+}
+
+/// A way for the mock to fail a request.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Failure {
+    /// Deezer's "quota exceeded" error.
+    Quota,
+    /// Deezer's "service busy" error.
+    ServiceBusy,
+    /// A 503 response.
+    Unavailable,
+}
+
+impl Failure {
+    fn response(self) -> HttpResponse {
+        let error = |code: u16| json!({ "error": { "type": "Exception", "code": code } });
+        match self {
+            Failure::Quota => HttpResponse::Ok().json(error(4)),
+            Failure::ServiceBusy => HttpResponse::Ok().json(error(700)),
+            Failure::Unavailable => HttpResponse::ServiceUnavailable().finish(),
+        }
+    }
+}
+
+/// Keeps count of the requests made to the mock, and the failures it still has
+/// to inject.
+#[derive(Default)]
+struct Requests {
+    count: AtomicUsize,
+    failures: Mutex<VecDeque<Failure>>,
+}
+
+async fn count_requests(
+    req: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<EitherBody<BoxBody>>, actix_web::Error> {
+    let requests = req
+        .app_data::<Data<Requests>>()
+        .expect("request state missing")
+        .clone();
+    requests.count.fetch_add(1, Ordering::SeqCst);
+    let failure = requests.failures.lock().unwrap().pop_front();
+    match failure {
+        Some(failure) => Ok(req.into_response(failure.response()).map_into_right_body()),
+        None => Ok(next.call(req).await?.map_into_left_body()),
+    }
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    q: Option<String>,
+    index: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// Removes the nested `albums`/`tracks` lists, which Deezer doesn't return.
+fn strip(value: &Value) -> Value {
+    let mut map: Map<String, Value> = value.as_object().cloned().unwrap_or_default();
+    map.remove("albums");
+    map.remove("tracks");
+    Value::Object(map)
+}
+
+fn not_found() -> HttpResponse {
+    // Deezer reports errors with a 200 status and an error object.
+    HttpResponse::Ok().json(json!({
+        "error": { "type": "DataException", "message": "no data", "code": 800 }
+    }))
+}
+
+fn page<'a>(
+    req: &HttpRequest,
+    query: &PageQuery,
+    items: impl IntoIterator<Item = &'a Value>,
+) -> HttpResponse {
+    let items: Vec<Value> = items.into_iter().map(strip).collect();
+    let index = query.index.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    let data: Vec<&Value> = items.iter().skip(index).take(limit).collect();
+
+    let mut body = json!({ "data": data, "total": items.len() });
+    if index + limit < items.len() {
+        let mut next = req.full_url();
+        next.query_pairs_mut()
+            .clear()
+            .extend_pairs(query.q.iter().map(|q| ("q", q.as_str())))
+            .append_pair("index", &(index + limit).to_string())
+            .append_pair("limit", &limit.to_string());
+        body["next"] = next.to_string().into();
+    }
+    HttpResponse::Ok().json(body)
+}
+
+async fn search_artist(
+    req: HttpRequest,
+    query: Query<PageQuery>,
+    catalogue: Data<Catalogue>,
+) -> HttpResponse {
+    let q = query.q.as_deref().unwrap_or_default().to_lowercase();
+    let matching = catalogue.artists.iter().filter(|a| {
+        a["name"]
+            .as_str()
+            .is_some_and(|name| name.to_lowercase().contains(&q))
+    });
+    page(&req, &query, matching)
+}
+
+async fn artist(path: web::Path<u64>, catalogue: Data<Catalogue>) -> HttpResponse {
+    match catalogue.artist(path.into_inner()) {
+        Some(artist) => HttpResponse::Ok().json(strip(artist)),
+        None => not_found(),
+    }
+}
+
+async fn artist_albums(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    query: Query<PageQuery>,
+    catalogue: Data<Catalogue>,
+) -> HttpResponse {
+    match catalogue.artist(path.into_inner()) {
+        Some(artist) => page(
+            &req,
+            &query,
+            artist["albums"].as_array().into_iter().flatten(),
+        ),
+        None => not_found(),
+    }
+}
+
+async fn album_tracks(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    query: Query<PageQuery>,
+    catalogue: Data<Catalogue>,
+) -> HttpResponse {
+    match catalogue.album(path.into_inner()) {
+        Some(album) => page(
+            &req,
+            &query,
+            album["tracks"].as_array().into_iter().flatten(),
+        ),
+        None => not_found(),
+    }
+}
+
+async fn track(path: web::Path<u64>, catalogue: Data<Catalogue>) -> HttpResponse {
+    match catalogue.track(path.into_inner()) {
+        Some(track) => HttpResponse::Ok().json(strip(track)),
+        None => not_found(),
+    }
+}
+
+/// A mock Deezer API listening on a random local port. The server is stopped
+/// when this is dropped.
+pub(crate) struct MockDeezer {
+    base: Url,
+    handle: ServerHandle,
+    requests: Arc<Requests>,
+}
+
+impl MockDeezer {
+    /// Starts the mock server on the current runtime.
+    pub(crate) async fn start() -> Self {
+        let catalogue: Value = serde_json::from_str(CATALOGUE).expect("invalid catalogue fixture");
+        let catalogue = Data::new(Catalogue {
+            artists: catalogue["artists"].as_array().cloned().unwrap_or_default(),
+        });
+
+        let requests = Data::new(Requests::default());
+
+        let server_requests = Data::clone(&requests);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(Data::clone(&catalogue))
+                .app_data(Data::clone(&server_requests))
+                .wrap(from_fn(count_requests))
+                .route("/search/artist", web::get().to(search_artist))
+                .route("/artist/{id}", web::get().to(artist))
+                .route("/artist/{id}/albums", web::get().to(artist_albums))
+                .route("/album/{id}/tracks", web::get().to(album_tracks))
+                .route("/track/{id}", web::get().to(track))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("could not bind mock server");
+
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        Self {
+            base: Url::parse(&format!("http://{addr}")).unwrap(),
+            handle,
+            requests: requests.into_inner(),
+        }
+    }
+
+    /// The number of requests the mock has received.
+    pub(crate) fn request_count(&self) -> usize {
+        self.requests.count.load(Ordering::SeqCst)
+    }
+
+    /// Makes the next `n` requests fail with `failure`.
+    pub(crate) fn fail_next(&self, n: usize, failure: Failure) {
+        let mut failures = self.requests.failures.lock().unwrap();
+        failures.extend(std::iter::repeat_n(failure, n));
+    }
+
+    /// The base URL of the mock API.
+    pub(crate) fn base_url(&self) -> Url {
+        self.base.clone()
+    }
+
+    /// Creates a [`Deezer`] client talking to the mock API. The mock has no rate
+    /// limit, so the client barely has one either.
+    pub(crate) fn client(&self) -> Deezer {
+        Deezer::from_config(&DeezerConfig {
+            api_base: self.base_url(),
+            rate_limit: RateLimitConfig {
+                requests: 1000,
+                per: TimeDelta::try_seconds(1).unwrap(),
+                burst: 1000,
+            },
+            ..Default::default()
+        })
+    }
+}
+
+impl Drop for MockDeezer {
+    fn drop(&mut self) {
+        #[allow(clippy::let_underscore_future)]
+        let _ = self.handle.stop(false);
+    }
+}
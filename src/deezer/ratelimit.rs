@@ -0,0 +1,227 @@
+//! A token bucket limiting how quickly requests are sent to the API.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use chrono::TimeDelta;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::deser_timedelta;
+
+/// Configures how many requests may be sent to the API in a given period.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// The number of requests allowed in any period.
+    pub requests: u32,
+    /// The length of the period.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub per: TimeDelta,
+    /// How many of the requests can be sent at once after a quiet spell. The rest
+    /// are spread out over the period. At most `requests`.
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        // Deezer allows 50 requests per 5 seconds; leave some headroom
+        Self {
+            requests: 45,
+            per: TimeDelta::try_seconds(5).unwrap(),
+            burst: 5,
+        }
+    }
+}
+
+/// A token bucket, which holds up to `burst` tokens and is refilled at a rate
+/// of `requests` per `per`. Every request takes one token. As a bucket that starts
+/// out full would let through `burst` requests too many in its first period, the
+/// times of the last `requests` requests are kept too, and no request is let
+/// through while all of them are within the last `per`.
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    /// Time taken to refill a single token.
+    interval: Duration,
+    last_refill: Instant,
+    /// The number of requests allowed in any period.
+    requests: usize,
+    per: Duration,
+    /// When the last `requests` requests were sent, oldest first.
+    sent: VecDeque<Instant>,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let requests = config.requests.max(1);
+        let capacity = config.burst.clamp(1, requests);
+        let per = config.per.to_std().unwrap_or_default();
+        Self {
+            capacity: f64::from(capacity),
+            tokens: f64::from(capacity),
+            interval: per / requests,
+            last_refill: Instant::now(),
+            requests: requests as usize,
+            per,
+            sent: VecDeque::with_capacity(requests as usize),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = if self.interval.is_zero() {
+            self.capacity
+        } else {
+            (self.tokens + elapsed.as_secs_f64() / self.interval.as_secs_f64()).min(self.capacity)
+        };
+    }
+
+    /// Takes a token at `now` if there is one, otherwise returns how long it will
+    /// take for one to become available.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        self.check(now)?;
+        self.tokens = (self.tokens - 1.0).max(0.0);
+        if self.sent.len() == self.requests {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(now);
+        Ok(())
+    }
+
+    /// Waits until a token is available, without taking it.
+    pub async fn ready(&mut self) {
+        while let Err(wait) = self.check(Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        // allow for rounding errors, so waiting the returned time is always enough
+        let token_wait = if self.tokens >= 1.0 - 1e-9 {
+            Duration::ZERO
+        } else {
+            self.interval.mul_f64(1.0 - self.tokens)
+        };
+        let window_wait = match self.sent.front() {
+            Some(&oldest) if self.sent.len() == self.requests => {
+                (oldest + self.per).saturating_duration_since(now)
+            }
+            _ => Duration::ZERO,
+        };
+        match token_wait.max(window_wait) {
+            Duration::ZERO => Ok(()),
+            wait => Err(wait),
+        }
+    }
+
+    /// Waits until a token is available and takes it.
+    pub async fn acquire(&mut self) {
+        while let Err(wait) = self.try_acquire(Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(requests: u32, burst: u32, per_secs: i64) -> TokenBucket {
+        TokenBucket::new(&RateLimitConfig {
+            requests,
+            per: TimeDelta::try_seconds(per_secs).unwrap(),
+            burst,
+        })
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        // 3 at once, then one every 600ms
+        let mut bucket = bucket(5, 3, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(bucket.try_acquire(start), Ok(()));
+        }
+
+        let wait = bucket.try_acquire(start).unwrap_err();
+        assert!(wait <= Duration::from_millis(600) && wait > Duration::from_millis(590));
+        assert!(bucket.try_acquire(start + wait / 2).is_err());
+        assert_eq!(bucket.try_acquire(start + wait), Ok(()));
+
+        // a long pause doesn't allow more than a full bucket at once
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(bucket.try_acquire(later), Ok(()));
+        }
+        assert!(bucket.try_acquire(later).is_err());
+    }
+
+    #[test]
+    fn test_steady_rate() {
+        let mut bucket = bucket(10, 2, 1);
+        let start = Instant::now();
+        let mut now = start;
+        let mut sent = 0;
+        while now < start + Duration::from_secs(5) {
+            match bucket.try_acquire(now) {
+                Ok(()) => sent += 1,
+                Err(wait) => now += wait,
+            }
+        }
+        // 10 a second, the bursts included
+        assert!((49..=50).contains(&sent), "{sent}");
+    }
+
+    #[test]
+    fn test_window_after_burst() {
+        // the burst and the refills in the rest of the period make up the limit, so
+        // the next request waits for the period to be over
+        let mut bucket = bucket(5, 3, 3);
+        let start = Instant::now();
+        let mut now = start;
+        let mut sent = Vec::new();
+        while sent.len() < 6 {
+            match bucket.try_acquire(now) {
+                Ok(()) => sent.push(now - start),
+                Err(wait) => now += wait,
+            }
+        }
+        assert_eq!(sent[4].as_millis(), 1200);
+        assert_eq!(sent[5], Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_window_limit() {
+        let config = RateLimitConfig::default();
+        let per = config.per.to_std().unwrap();
+        let mut bucket = TokenBucket::new(&config);
+        let start = Instant::now();
+
+        // send as fast as allowed, with quiet spells long enough to fill the bucket
+        let mut now = start;
+        let mut sent = Vec::new();
+        for round in 0..4 {
+            let until = now + per * (round + 1);
+            while now < until {
+                match bucket.try_acquire(now) {
+                    Ok(()) => sent.push(now),
+                    Err(wait) => now += wait,
+                }
+            }
+            now += per * 2;
+        }
+
+        for (i, &from) in sent.iter().enumerate() {
+            let in_window = sent[i..].iter().take_while(|&&t| t < from + per).count();
+            assert!(
+                in_window <= config.requests as usize,
+                "{in_window} requests in the window from {:?}",
+                from - start
+            );
+        }
+    }
+}
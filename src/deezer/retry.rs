@@ -0,0 +1,47 @@
+//! Retrying requests that failed for reasons that are likely to go away.
+
+use std::time::Duration;
+
+use chrono::TimeDelta;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::deser_timedelta;
+
+/// Configures how failed requests are retried.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// How many times a request is attempted in total, including the first try.
+    pub attempts: u32,
+    /// The delay before the first retry, which is doubled for every further one.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub base_delay: TimeDelta,
+    /// The longest delay between two attempts.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub max_delay: TimeDelta,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            base_delay: TimeDelta::try_milliseconds(500).unwrap(),
+            max_delay: TimeDelta::try_seconds(10).unwrap(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Gets how long to wait after the `attempt`th attempt (counting from 1) failed.
+    /// Half of the delay is random, so that requests failing together don't all
+    /// retry together.
+    pub fn delay<R: Rng>(&self, attempt: u32, rng: &mut R) -> Duration {
+        let base = self.base_delay.to_std().unwrap_or_default();
+        let max = self.max_delay.to_std().unwrap_or_default();
+        let delay = base
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(max);
+        delay / 2 + delay.mul_f64(rng.gen::<f64>() / 2.0)
+    }
+}
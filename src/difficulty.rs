@@ -0,0 +1,190 @@
+//! Quiz difficulty levels, which decide which of an artist's tracks are asked
+//! about, and in what order, going by how popular they are.
+
+use std::cmp::Reverse;
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::db::TrackInfo;
+
+/// The fewest tracks a difficulty narrows an artist's tracks down to, so that
+/// small discographies still make for a quiz.
+const MIN_POOL: usize = 10;
+
+/// How much likelier the most popular track in a pool is to be asked early than
+/// the least popular one, or the other way around for [`Difficulty::Hard`].
+const POPULARITY_BIAS: f64 = 10.0;
+
+/// How hard a quiz is.
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+pub enum Difficulty {
+    /// The most popular half of the tracks, the biggest hits likeliest to come first.
+    Easy,
+    /// All tracks, in random order.
+    #[default]
+    Normal,
+    /// The least popular half of the tracks, the most obscure likeliest to come first.
+    Hard,
+    /// Only the least popular quarter of the tracks, in random order.
+    DeepCuts,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 4] = [
+        Difficulty::Easy,
+        Difficulty::Normal,
+        Difficulty::Hard,
+        Difficulty::DeepCuts,
+    ];
+
+    /// The name of the difficulty in URLs.
+    pub fn name(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Normal => "normal",
+            Difficulty::Hard => "hard",
+            Difficulty::DeepCuts => "deep-cuts",
+        }
+    }
+
+    /// The name of the difficulty as shown to players.
+    pub fn label(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "Easy",
+            Difficulty::Normal => "Normal",
+            Difficulty::Hard => "Hard",
+            Difficulty::DeepCuts => "Deep cuts",
+        }
+    }
+
+    /// Picks the tracks to ask about out of `tracks`, in the order they're asked in.
+    /// Tracks without a rank count as the least popular; if none have one, every
+    /// difficulty is the same as [`Difficulty::Normal`].
+    pub fn select<R: Rng>(self, mut tracks: Vec<TrackInfo>, rng: &mut R) -> Vec<TrackInfo> {
+        let share = match self {
+            _ if tracks.iter().all(|t| t.rank.is_none()) => None,
+            Difficulty::Normal => None,
+            Difficulty::Easy | Difficulty::Hard => Some(2),
+            Difficulty::DeepCuts => Some(4),
+        };
+        let Some(share) = share else {
+            tracks.shuffle(rng);
+            return tracks;
+        };
+
+        // most popular first
+        tracks.sort_by_key(|t| Reverse(t.rank.unwrap_or(0)));
+        let len = tracks.len();
+        let size = (len / share).clamp(MIN_POOL.min(len), len);
+        let pool = if self == Difficulty::Easy {
+            tracks.truncate(size);
+            tracks
+        } else {
+            tracks.split_off(len - size)
+        };
+
+        // a weighted shuffle, giving each track a random key skewed by its weight
+        let last = pool.len().saturating_sub(1).max(1) as f64;
+        let mut keyed: Vec<_> = pool
+            .into_iter()
+            .enumerate()
+            .map(|(i, track)| {
+                let weight = self.weight(1.0 - i as f64 / last);
+                (rng.gen::<f64>().powf(weight.recip()), track)
+            })
+            .collect();
+        keyed.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        keyed.into_iter().map(|(_, track)| track).collect()
+    }
+
+    /// How likely a track is to be asked early, given its `popularity` within the
+    /// pool, from 0 for the least popular track to 1 for the most popular.
+    fn weight(self, popularity: f64) -> f64 {
+        match self {
+            Difficulty::Easy => 1.0 + (POPULARITY_BIAS - 1.0) * popularity,
+            Difficulty::Hard => 1.0 + (POPULARITY_BIAS - 1.0) * (1.0 - popularity),
+            Difficulty::Normal | Difficulty::DeepCuts => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 40 tracks, where the track with id `i` has rank `i`.
+    fn tracks() -> Vec<TrackInfo> {
+        (0..40)
+            .map(|id| TrackInfo {
+                rank: Some(id as i32),
+                ..TrackInfo::for_test(id, &format!("Track {id}"))
+            })
+            .collect()
+    }
+
+    fn ids(tracks: &[TrackInfo]) -> Vec<i64> {
+        let mut ids: Vec<_> = tracks.iter().map(|t| t.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_name() {
+        for difficulty in Difficulty::ALL {
+            assert_eq!(serde_json::to_value(difficulty).unwrap(), difficulty.name());
+        }
+    }
+
+    #[test]
+    fn test_pools() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let all = Difficulty::Normal.select(tracks(), &mut rng);
+        assert_eq!(ids(&all), (0..40).collect::<Vec<_>>());
+        let easy = Difficulty::Easy.select(tracks(), &mut rng);
+        assert_eq!(ids(&easy), (20..40).collect::<Vec<_>>());
+        let hard = Difficulty::Hard.select(tracks(), &mut rng);
+        assert_eq!(ids(&hard), (0..20).collect::<Vec<_>>());
+        let deep_cuts = Difficulty::DeepCuts.select(tracks(), &mut rng);
+        assert_eq!(ids(&deep_cuts), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_small_and_unranked() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let few: Vec<_> = tracks().into_iter().take(12).collect();
+        assert_eq!(Difficulty::DeepCuts.select(few.clone(), &mut rng).len(), 10);
+        let five: Vec<_> = few.into_iter().take(5).collect();
+        assert_eq!(Difficulty::Easy.select(five, &mut rng).len(), 5);
+
+        let unranked: Vec<_> = tracks()
+            .into_iter()
+            .map(|t| TrackInfo { rank: None, ..t })
+            .collect();
+        let normal = Difficulty::Normal.select(unranked.clone(), &mut StdRng::seed_from_u64(7));
+        let easy = Difficulty::Easy.select(unranked, &mut StdRng::seed_from_u64(7));
+        assert_eq!(
+            normal.iter().map(|t| t.id).collect::<Vec<_>>(),
+            easy.iter().map(|t| t.id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_order() {
+        // summed over many quizzes, the first questions should lean towards hits on
+        // easy and towards obscure tracks on hard
+        let mut rng = StdRng::seed_from_u64(42);
+        let first_ranks = |difficulty: Difficulty, rng: &mut StdRng| -> f64 {
+            (0..200)
+                .flat_map(|_| difficulty.select(tracks(), rng).into_iter().take(5))
+                .map(|t| f64::from(t.rank.unwrap()))
+                .sum::<f64>()
+                / 1000.0
+        };
+        // the pools are 20..40 and 0..20, with means of 29.5 and 9.5
+        assert!(first_ranks(Difficulty::Easy, &mut rng) > 32.0);
+        assert!(first_ranks(Difficulty::Hard, &mut rng) < 7.0);
+    }
+}
@@ -0,0 +1,302 @@
+//! Strategies for picking the wrong options offered alongside the right answer,
+//! so that they aren't trivially told apart from it.
+
+use chrono::Datelike;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::db::TrackInfo;
+
+/// Release years are compared in windows of this many years.
+const ERA_YEARS: u32 = 3;
+
+/// Title lengths are compared in steps of this many characters.
+const TITLE_LENGTH_STEP: u32 = 4;
+
+/// How many steps of [`TITLE_LENGTH_STEP`] apart titles in different scripts are.
+const SCRIPT_DISTANCE: u32 = 100;
+
+/// Popularity is compared in steps of this much on a natural log scale of rank,
+/// as ranks span several orders of magnitude.
+const POPULARITY_STEP: f64 = 0.5;
+
+/// How the wrong options for a question are picked.
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+pub enum DistractorStrategy {
+    /// Any of the artist's other tracks.
+    #[default]
+    Random,
+    /// Tracks from the same album as the answer.
+    SameAlbum,
+    /// Tracks released around the same time as the answer.
+    SameEra,
+    /// Tracks with titles of a similar length, in the same script.
+    SimilarTitle,
+    /// Tracks about as popular as the answer.
+    SimilarPopularity,
+}
+
+impl DistractorStrategy {
+    pub const ALL: [DistractorStrategy; 5] = [
+        DistractorStrategy::Random,
+        DistractorStrategy::SameAlbum,
+        DistractorStrategy::SameEra,
+        DistractorStrategy::SimilarTitle,
+        DistractorStrategy::SimilarPopularity,
+    ];
+
+    /// The name of the strategy in URLs.
+    pub fn name(self) -> &'static str {
+        match self {
+            DistractorStrategy::Random => "random",
+            DistractorStrategy::SameAlbum => "same-album",
+            DistractorStrategy::SameEra => "same-era",
+            DistractorStrategy::SimilarTitle => "similar-title",
+            DistractorStrategy::SimilarPopularity => "similar-popularity",
+        }
+    }
+
+    /// The name of the strategy as shown to players.
+    pub fn label(self) -> &'static str {
+        match self {
+            DistractorStrategy::Random => "Any song",
+            DistractorStrategy::SameAlbum => "Same album",
+            DistractorStrategy::SameEra => "Same era",
+            DistractorStrategy::SimilarTitle => "Similar titles",
+            DistractorStrategy::SimilarPopularity => "As popular",
+        }
+    }
+
+    /// Picks up to `count` titles out of `candidates` to go alongside `answer`.
+    /// `candidates` hold a single version of each song, as grouped with
+    /// [`group_by_title`](crate::quiz::group_by_title), so leaving out `answer`
+    /// itself leaves out its song. The closest
+    /// candidates are picked first, in random order among equally close ones, and
+    /// candidates the strategy can't judge, e.g. for lack of a release date, last.
+    pub fn pick<R: Rng>(
+        self,
+        answer: &TrackInfo,
+        candidates: &[TrackInfo],
+        count: usize,
+        rng: &mut R,
+    ) -> Vec<String> {
+        let other_song = |c: &&TrackInfo| c.id != answer.id;
+        if self == DistractorStrategy::Random {
+            return candidates
+                .choose_multiple(rng, count + 1) // one extra, in case the answer is among them
+                .filter(other_song)
+                .map(|c| c.title.clone())
+                .take(count)
+                .collect();
+        }
+
+        let mut candidates: Vec<_> = candidates
+            .iter()
+            .filter(other_song)
+            .map(|c| (self.distance(answer, c).unwrap_or(u32::MAX), c))
+            .collect();
+        // the sort is stable, so this keeps equally close candidates in random order
+        candidates.shuffle(rng);
+        candidates.sort_by_key(|&(distance, _)| distance);
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|(_, c)| c.title.clone())
+            .collect()
+    }
+
+    /// How far `candidate` is from being a good stand-in for `answer`, or `None`
+    /// if that can't be told.
+    fn distance(self, answer: &TrackInfo, candidate: &TrackInfo) -> Option<u32> {
+        match self {
+            DistractorStrategy::Random => Some(0),
+            DistractorStrategy::SameAlbum => Some(u32::from(candidate.album_id != answer.album_id)),
+            DistractorStrategy::SameEra => {
+                let answer = answer.album_release_date?.year();
+                let candidate = candidate.album_release_date?.year();
+                Some(answer.abs_diff(candidate) / ERA_YEARS)
+            }
+            DistractorStrategy::SimilarTitle => {
+                let length = |t: &TrackInfo| t.title.chars().count() as u32;
+                let steps = length(answer).abs_diff(length(candidate)) / TITLE_LENGTH_STEP;
+                if script(&answer.title) == script(&candidate.title) {
+                    Some(steps)
+                } else {
+                    Some(steps + SCRIPT_DISTANCE)
+                }
+            }
+            DistractorStrategy::SimilarPopularity => {
+                let log_rank = |t: &TrackInfo| t.rank.map(|r| f64::from(r.max(0)).ln_1p());
+                let difference = (log_rank(answer)? - log_rank(candidate)?).abs();
+                Some((difference / POPULARITY_STEP) as u32)
+            }
+        }
+    }
+}
+
+/// A rough grouping of writing systems, standing in for a title's language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    /// Chinese, Japanese and Korean.
+    Cjk,
+    Other,
+}
+
+/// Gets the script most of the letters in `title` are written in.
+fn script(title: &str) -> Script {
+    let mut counts = [0usize; 5];
+    for c in title.chars().filter(|c| c.is_alphabetic()) {
+        let script = match u32::from(c) {
+            0x0000..=0x024f | 0x1e00..=0x1eff => Script::Latin,
+            0x0370..=0x03ff | 0x1f00..=0x1fff => Script::Greek,
+            0x0400..=0x052f => Script::Cyrillic,
+            0x3040..=0x30ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xac00..=0xd7af => Script::Cjk,
+            _ => Script::Other,
+        };
+        counts[script as usize] += 1;
+    }
+    [
+        Script::Latin,
+        Script::Cyrillic,
+        Script::Greek,
+        Script::Cjk,
+        Script::Other,
+    ]
+    .into_iter()
+    .max_by_key(|&s| (counts[s as usize], s == Script::Latin))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn track(id: i64, title: &str, album_id: i64, year: i32, rank: i32) -> TrackInfo {
+        TrackInfo {
+            album_id,
+            album_release_date: NaiveDate::from_ymd_opt(year, 1, 1),
+            rank: Some(rank),
+            ..TrackInfo::for_test(id, title)
+        }
+    }
+
+    fn catalogue() -> Vec<TrackInfo> {
+        vec![
+            track(1, "Answer", 1, 2008, 1_000),
+            track(2, "Album Mate", 1, 2008, 900_000),
+            track(3, "Another Album Mate", 1, 2008, 500),
+            track(4, "Same Year", 2, 2009, 2_000_000),
+            track(5, "Also Same Year", 2, 2009, 700_000),
+            track(6, "Much Later", 3, 2023, 1_200),
+            track(7, "Later Still", 3, 2023, 900),
+            track(8, "Ответ", 4, 2015, 400_000),
+            track(9, "A Very Very Long Title Indeed", 4, 2015, 1_100),
+            track(10, "Tune", 5, 2023, 300_000),
+        ]
+    }
+
+    fn pick(strategy: DistractorStrategy, seed: u64) -> Vec<String> {
+        let tracks = catalogue();
+        let mut picked = strategy.pick(&tracks[0], &tracks, 3, &mut StdRng::seed_from_u64(seed));
+        picked.sort();
+        picked
+    }
+
+    #[test]
+    fn test_name() {
+        for strategy in DistractorStrategy::ALL {
+            assert_eq!(serde_json::to_value(strategy).unwrap(), strategy.name());
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        for seed in 0..20 {
+            let picked = pick(DistractorStrategy::Random, seed);
+            assert_eq!(picked.len(), 3);
+            assert!(!picked.contains(&"Answer".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_same_album() {
+        for seed in 0..20 {
+            let picked = pick(DistractorStrategy::SameAlbum, seed);
+            assert_eq!(picked.len(), 3);
+            assert!(picked.contains(&"Album Mate".to_owned()));
+            assert!(picked.contains(&"Another Album Mate".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_same_era() {
+        let same_era = [
+            "Album Mate",
+            "Also Same Year",
+            "Another Album Mate",
+            "Same Year",
+        ];
+        let mut seen = HashSet::new();
+        for seed in 0..20 {
+            let picked = pick(DistractorStrategy::SameEra, seed);
+            assert_eq!(picked.len(), 3);
+            assert!(picked.iter().all(|t| same_era.contains(&t.as_str())));
+            seen.extend(picked);
+        }
+        // equally close candidates take turns
+        assert_eq!(seen.len(), same_era.len());
+    }
+
+    #[test]
+    fn test_similar_title() {
+        for seed in 0..20 {
+            let picked = pick(DistractorStrategy::SimilarTitle, seed);
+            assert!(picked.contains(&"Tune".to_owned()));
+            assert!(picked.contains(&"Same Year".to_owned()));
+            assert!(!picked.contains(&"Ответ".to_owned()));
+            assert!(!picked.contains(&"A Very Very Long Title Indeed".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_similar_popularity() {
+        for seed in 0..20 {
+            assert_eq!(
+                pick(DistractorStrategy::SimilarPopularity, seed),
+                ["A Very Very Long Title Indeed", "Later Still", "Much Later"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_metadata_last() {
+        let mut tracks = catalogue();
+        for track in &mut tracks[1..7] {
+            track.album_release_date = None;
+        }
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut picked = DistractorStrategy::SameEra.pick(&tracks[0], &tracks, 3, &mut rng);
+        picked.sort();
+        assert_eq!(picked, ["A Very Very Long Title Indeed", "Tune", "Ответ"]);
+    }
+
+    #[test]
+    fn test_script() {
+        assert_eq!(script("Summoning 101"), Script::Latin);
+        assert_eq!(script("Café"), Script::Latin);
+        assert_eq!(script("Ответ"), Script::Cyrillic);
+        assert_eq!(script("Ωμέγα"), Script::Greek);
+        assert_eq!(script("夜に駆ける"), Script::Cjk);
+        assert_eq!(script("Yoru ni 駆ける"), Script::Latin);
+        assert_eq!(script("101"), Script::Latin);
+    }
+}
@@ -0,0 +1,150 @@
+//! Removing artists nobody has opened in a while from the cache, so that it
+//! doesn't grow forever.
+
+use std::sync::Arc;
+
+use chrono::TimeDelta;
+use serde::Deserialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{db::UnusedArtist, deser_timedelta, state::QuizState};
+
+/// Configures garbage collection of cached artists.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GcConfig {
+    /// How often garbage is collected while the server is running. 0 disables it,
+    /// leaving only the `gc` command.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub interval: TimeDelta,
+    /// How long an artist may go without being opened before it's removed.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub idle: TimeDelta,
+    /// The most artists kept in the cache, the least recently opened being
+    /// removed first.
+    pub max_artists: Option<usize>,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            interval: TimeDelta::try_days(1).unwrap(),
+            idle: TimeDelta::try_days(90).unwrap(),
+            max_artists: None,
+        }
+    }
+}
+
+/// Collects garbage every `config.interval`, forever.
+pub async fn run(state: Arc<QuizState>, config: GcConfig) {
+    let Ok(period) = config.interval.to_std() else {
+        log::error!("Invalid garbage collection interval {}", config.interval);
+        return;
+    };
+    if period.is_zero() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(e) = collect(&state, &config, false).await {
+            log::error!("Could not collect garbage: {e}");
+        }
+    }
+}
+
+/// Removes the artists that `config` says are garbage, logging each of them.
+/// With `dry_run`, they're only logged.
+pub async fn collect(
+    state: &QuizState,
+    config: &GcConfig,
+    dry_run: bool,
+) -> Result<Vec<UnusedArtist>, sqlx::Error> {
+    let removed = state
+        .collect_garbage(config.idle, config.max_artists, dry_run)
+        .await?;
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    for artist in &removed {
+        log::info!(
+            "{verb} artist {} ({}), last opened {}",
+            artist.id,
+            artist.title,
+            artist.last_accessed_at
+        );
+    }
+    log::info!("{verb} {} artists", removed.len());
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{PgPool, Postgres};
+
+    use super::*;
+    use crate::refresher::warm;
+    use crate::source::tests::fake_source;
+
+    async fn set_accessed(pool: &PgPool, id: i64, days_ago: i32) {
+        sqlx::query(
+            "update artists set last_accessed_at = now() - make_interval(days => $2) where id = $1",
+        )
+        .bind(id)
+        .bind(days_ago)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn cached(pool: &PgPool) -> Vec<i64> {
+        sqlx::query_scalar("select id from artists order by id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_collect(pool: sqlx::Pool<Postgres>) {
+        let mut source = fake_source();
+        for id in [2, 3] {
+            let mut other = source.artists[0].clone();
+            other.id = id;
+            other.albums.clear();
+            source.artists.push(other);
+        }
+        let state = QuizState::with_pool(
+            pool.clone(),
+            TimeDelta::try_hours(1).unwrap(),
+            None,
+            Arc::new(source),
+        );
+        assert_eq!(warm(&state, [1, 2, 3]).await, 0);
+        set_accessed(&pool, 1, 100).await;
+        set_accessed(&pool, 2, 10).await;
+        set_accessed(&pool, 3, 1).await;
+
+        let config = GcConfig::default();
+        let removed = collect(&state, &config, true).await.unwrap();
+        assert_eq!(removed.iter().map(|a| a.id).collect::<Vec<_>>(), [1]);
+        assert_eq!(cached(&pool).await, [1, 2, 3]);
+
+        collect(&state, &config, false).await.unwrap();
+        assert_eq!(cached(&pool).await, [2, 3]);
+        let tracks: i64 = sqlx::query_scalar("select count(*) from tracks")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(tracks, 0);
+
+        // opening an artist keeps it around
+        state.get_artist(2).await.unwrap();
+        let config = GcConfig {
+            max_artists: Some(1),
+            ..GcConfig::default()
+        };
+        let removed = collect(&state, &config, false).await.unwrap();
+        assert_eq!(removed.iter().map(|a| a.id).collect::<Vec<_>>(), [3]);
+        assert_eq!(cached(&pool).await, [2]);
+    }
+}
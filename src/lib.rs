@@ -1,27 +1,67 @@
 use chrono::TimeDelta;
+use gc::GcConfig;
+use refresher::RefresherConfig;
 use serde::de::Error;
 use serde::{de::Unexpected, Deserialize, Deserializer};
+use source::SourceConfig;
+use state::NegativeCacheConfig;
+use titles::TitleConfig;
+use trackcache::TrackCacheConfig;
 
 pub mod db;
 pub mod deezer;
+pub mod difficulty;
+pub mod distractors;
+pub mod gc;
+pub mod library;
 pub mod loading;
+pub mod quiz;
+pub mod refresher;
 pub mod routing;
+pub mod source;
 pub mod state;
+pub mod titles;
+pub mod trackcache;
 
 const WEBSITE_NAME: &str = "quiz.make.id.lv";
 
 #[derive(Deserialize)]
 pub struct Config {
     pub database_url: String,
+    /// How long cached artists are considered fresh.
     #[serde(deserialize_with = "deser_timedelta")]
     pub cache_duration: TimeDelta,
+    /// How long stale artists may still be served while they're refreshed in the
+    /// background. Past this, requests wait for the refresh. If unset, stale
+    /// artists are always served.
+    #[serde(default, deserialize_with = "deser_opt_timedelta")]
+    pub cache_max_age: Option<TimeDelta>,
+    /// How long loading an artist into the cache may take before it's given up
+    /// on. If unset, loads are never given up on.
+    #[serde(default, deserialize_with = "deser_opt_timedelta")]
+    pub load_timeout: Option<TimeDelta>,
     pub bind_address: String,
+    #[serde(default)]
+    pub source: SourceConfig,
+    #[serde(default)]
+    pub refresher: RefresherConfig,
+    #[serde(default)]
+    pub negative_cache: NegativeCacheConfig,
+    #[serde(default)]
+    pub track_cache: TrackCacheConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub titles: TitleConfig,
 }
 
-/// Parses a timedelta in the format "1d 2h 3m 2s".
+/// Parses a timedelta in the format "1d 2h 3m 2s 500ms".
 pub fn parse_timedelta(s: &str) -> Option<TimeDelta> {
     s.split_whitespace()
         .map(|x| {
+            if let Some(ms) = x.strip_suffix("ms") {
+                return TimeDelta::try_milliseconds(ms.parse().ok()?);
+            }
             let (idx, c) = x.char_indices().next_back()?;
             let num: i64 = x[..idx].parse().ok()?;
             let comp = match c {
@@ -44,3 +84,11 @@ where
     let s: String = Deserialize::deserialize(deserializer)?;
     parse_timedelta(&s).ok_or_else(|| D::Error::invalid_value(Unexpected::Str(&s), &"a duration"))
 }
+
+/// Like [`deser_timedelta`], but for optional values.
+pub fn deser_opt_timedelta<'de, D>(deserializer: D) -> Result<Option<TimeDelta>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deser_timedelta(deserializer).map(Some)
+}
@@ -0,0 +1,530 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, StandardTagKey, Tag},
+    probe::Hint,
+};
+use thiserror::Error;
+
+use crate::{
+    db::{AlbumInfo, ArtistInfo, TrackInfo},
+    source::{self, MusicSource, Priority},
+};
+
+pub mod clip;
+#[cfg(test)]
+pub(crate) mod test_files;
+
+/// File extensions that are considered when scanning the library.
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "oga"];
+
+/// Image used for artists and albums, as the library has no artwork of its own.
+const PLACEHOLDER_IMAGE: &str = "/static/img/placeholder.svg";
+
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+}
+
+/// Computes a stable 31-bit id for `key` using FNV-1a, so that ids stay the same
+/// across rescans and fit in the `u32` artist ids used in URLs. A non-zero `salt`
+/// gives another id for the same key.
+fn stable_id(key: &str, salt: u32) -> i64 {
+    let salt = if salt == 0 {
+        &[][..]
+    } else {
+        &salt.to_le_bytes()[..]
+    };
+    let hash = key
+        .bytes()
+        .chain(salt.iter().copied())
+        .fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+    (hash & 0x7fff_ffff) as i64
+}
+
+/// The ids of a set of keys. When keys hash to the same id, the first of them in
+/// sorted order keeps it, and the others are rehashed with a salt until they get
+/// one of their own. Ids thus depend on the keys alone, not on the order files are
+/// scanned in, and only keys that collide can have their ids moved by others.
+struct Ids {
+    ids: HashMap<String, i64>,
+}
+
+impl Ids {
+    fn new(keys: impl IntoIterator<Item = String>) -> Self {
+        let keys: BTreeSet<_> = keys.into_iter().collect();
+        let mut taken: HashMap<i64, &str> = HashMap::new();
+        let mut ids = HashMap::new();
+        for key in &keys {
+            let id = (0..)
+                .map(|salt| stable_id(key, salt))
+                .find(|id| match taken.get(id) {
+                    Some(other) => {
+                        log::warn!("library ids of {key:?} and {other:?} collide, rehashing");
+                        false
+                    }
+                    None => true,
+                })
+                .unwrap();
+            taken.insert(id, key);
+            ids.insert(key.clone(), id);
+        }
+        Self { ids }
+    }
+
+    /// Gets the id of `key`, which has to be one of the keys the ids were made for.
+    fn get(&self, key: &str) -> i64 {
+        self.ids[key]
+    }
+}
+
+/// Tags read from a single audio file.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct FileTags {
+    artist: Option<String>,
+    album_artist: Option<String>,
+    album: Option<String>,
+    title: Option<String>,
+    track_number: Option<String>,
+    disc_number: Option<String>,
+    date: Option<String>,
+    isrc: Option<String>,
+    /// Not a tag, but read along with them: the length of the file in seconds.
+    duration: Option<i32>,
+}
+
+impl FileTags {
+    fn artist_name(&self) -> &str {
+        self.album_artist
+            .as_deref()
+            .or(self.artist.as_deref())
+            .unwrap_or("Unknown artist")
+    }
+
+    fn album_title(&self) -> &str {
+        self.album.as_deref().unwrap_or("Unknown album")
+    }
+
+    /// Tells albums apart, as ones with the same title by different artists aren't the same.
+    fn album_key(&self) -> String {
+        format!("{}\0{}", self.artist_name(), self.album_title())
+    }
+
+    fn apply(&mut self, tags: &[Tag]) {
+        for tag in tags {
+            let slot = match tag.std_key {
+                Some(StandardTagKey::Artist) => &mut self.artist,
+                Some(StandardTagKey::AlbumArtist) => &mut self.album_artist,
+                Some(StandardTagKey::Album) => &mut self.album,
+                Some(StandardTagKey::TrackTitle) => &mut self.title,
+                Some(StandardTagKey::TrackNumber) => &mut self.track_number,
+                Some(StandardTagKey::DiscNumber) => &mut self.disc_number,
+                Some(StandardTagKey::Date | StandardTagKey::ReleaseDate) => &mut self.date,
+                Some(StandardTagKey::IdentIsrc) => &mut self.isrc,
+                _ => continue,
+            };
+            let value = tag.value.to_string();
+            if slot.is_none() && !value.trim().is_empty() {
+                *slot = Some(value.trim().to_owned());
+            }
+        }
+    }
+}
+
+/// Parses a track or disc number tag, which may also hold the total, as in `3/12`.
+fn parse_number(tag: &str) -> Option<i32> {
+    tag.split('/').next()?.trim().parse().ok()
+}
+
+/// Parses a date tag, which is either a full date or just a year, in which case
+/// the year's first day is used.
+fn parse_date(tag: &str) -> Option<NaiveDate> {
+    tag.get(..10)
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+        .or_else(|| NaiveDate::from_ymd_opt(tag.get(..4)?.parse().ok()?, 1, 1))
+}
+
+/// Reads the tags of the audio file at `path`.
+fn read_tags(path: &Path) -> Result<FileTags, Box<dyn std::error::Error>> {
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mss = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut probed = symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    let mut tags = FileTags::default();
+    // tags inside the container take precedence over ones in front of it (e.g. ID3v2)
+    if let Some(rev) = probed.format.metadata().current() {
+        tags.apply(rev.tags());
+    }
+    if let Some(rev) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        tags.apply(rev.tags());
+    }
+    tags.duration = probed.format.default_track().and_then(|track| {
+        let params = &track.codec_params;
+        let time = params.time_base?.calc_time(params.n_frames?);
+        i32::try_from(time.seconds).ok()
+    });
+    Ok(tags)
+}
+
+struct LibraryAlbum {
+    info: AlbumInfo,
+    tracks: Vec<TrackInfo>,
+}
+
+struct LibraryArtist {
+    info: ArtistInfo,
+    albums: Vec<LibraryAlbum>,
+}
+
+/// A music source backed by a directory of MP3, FLAC and Ogg files. Artists, albums
+/// and titles are taken from the files' tags, and previews are served by the quiz itself.
+pub struct LibrarySource {
+    artists: Vec<LibraryArtist>,
+    paths: HashMap<i64, PathBuf>,
+}
+
+impl LibrarySource {
+    /// Scans `root` recursively for audio files.
+    pub fn scan(root: &Path) -> Result<Self, LibraryError> {
+        let mut files = Vec::new();
+        collect_files(root, &mut files)?;
+        files.sort();
+
+        let scanned: Vec<_> = files
+            .into_iter()
+            .filter_map(|path| match read_tags(&path) {
+                Ok(tags) => Some((path, tags)),
+                Err(e) => {
+                    log::warn!("could not read tags of {}: {e}", path.display());
+                    None
+                }
+            })
+            .collect();
+        let relative = |path: &Path| {
+            path.strip_prefix(root)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        // all keys are known before handing out ids, so that they don't depend on scan order
+        let artist_ids = Ids::new(scanned.iter().map(|(_, t)| t.artist_name().to_owned()));
+        let album_ids = Ids::new(scanned.iter().map(|(_, t)| t.album_key()));
+        let track_ids = Ids::new(scanned.iter().map(|(path, _)| relative(path)));
+
+        let mut artists: Vec<LibraryArtist> = Vec::new();
+        let mut paths = HashMap::new();
+        for (path, tags) in scanned {
+            let artist_name = tags.artist_name().to_owned();
+            let album_title = tags.album_title().to_owned();
+            let album_id = album_ids.get(&tags.album_key());
+            let title = tags.title.unwrap_or_else(|| {
+                path.file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+
+            let artist_id = artist_ids.get(&artist_name);
+            let artist_idx = match artists.iter().position(|a| a.info.id == artist_id) {
+                Some(idx) => idx,
+                None => {
+                    artists.push(LibraryArtist {
+                        info: ArtistInfo {
+                            id: artist_id,
+                            title: artist_name.clone(),
+                            icon_url: PLACEHOLDER_IMAGE.to_owned(),
+                            updated_at: Utc::now(),
+                        },
+                        albums: Vec::new(),
+                    });
+                    artists.len() - 1
+                }
+            };
+            let artist = &mut artists[artist_idx];
+
+            let album_idx = match artist.albums.iter().position(|a| a.info.id == album_id) {
+                Some(idx) => idx,
+                None => {
+                    artist.albums.push(LibraryAlbum {
+                        info: AlbumInfo {
+                            id: album_id,
+                            artist: artist_id,
+                            title: album_title.clone(),
+                            cover_url: PLACEHOLDER_IMAGE.to_owned(),
+                            release_date: tags.date.as_deref().and_then(parse_date),
+                        },
+                        tracks: Vec::new(),
+                    });
+                    artist.albums.len() - 1
+                }
+            };
+
+            let track_id = track_ids.get(&relative(&path));
+            let album = &mut artist.albums[album_idx];
+            album.tracks.push(TrackInfo {
+                id: track_id,
+                album_title,
+                album_cover_url: PLACEHOLDER_IMAGE.to_owned(),
+                album_id,
+                album_release_date: album.info.release_date,
+                title,
+                preview_url: format!("/library/tracks/{track_id}"),
+                duration: tags.duration,
+                rank: None,
+                explicit: None,
+                track_position: tags.track_number.as_deref().and_then(parse_number),
+                disc_number: tags.disc_number.as_deref().and_then(parse_number),
+                isrc: tags.isrc,
+            });
+            paths.insert(track_id, path);
+        }
+
+        log::info!(
+            "scanned library at {}: {} artists, {} tracks",
+            root.display(),
+            artists.len(),
+            paths.len()
+        );
+        Ok(Self { artists, paths })
+    }
+
+    fn find_artist(&self, id: u32) -> Result<&LibraryArtist, source::Error> {
+        self.artists
+            .iter()
+            .find(|a| a.info.id == i64::from(id))
+            .ok_or(source::Error::NotFound)
+    }
+}
+
+/// Collects the audio files in `dir` and its subdirectories. Symlinks to
+/// directories aren't followed, as they could lead into a loop.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            collect_files(&path, files)?;
+        } else if file_type.is_symlink() && path.is_dir() {
+            log::info!("not following symlink to directory {}", path.display());
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl MusicSource for LibrarySource {
+    async fn search_artists(
+        &self,
+        q: &str,
+        index: u32,
+        limit: u32,
+    ) -> Result<Vec<ArtistInfo>, source::Error> {
+        let q = q.to_lowercase();
+        Ok(self
+            .artists
+            .iter()
+            .filter(|a| a.info.title.to_lowercase().contains(&q))
+            .skip(index as usize)
+            .take(limit as usize)
+            .map(|a| ArtistInfo {
+                updated_at: Utc::now(),
+                ..a.info.clone()
+            })
+            .collect())
+    }
+
+    async fn artist(&self, id: u32, _priority: Priority) -> Result<ArtistInfo, source::Error> {
+        Ok(ArtistInfo {
+            updated_at: Utc::now(),
+            ..self.find_artist(id)?.info.clone()
+        })
+    }
+
+    async fn artist_albums(&self, id: u32) -> Result<Vec<AlbumInfo>, source::Error> {
+        Ok(self
+            .find_artist(id)?
+            .albums
+            .iter()
+            .map(|a| a.info.clone())
+            .collect())
+    }
+
+    async fn album_tracks(&self, album: &AlbumInfo) -> Result<Vec<TrackInfo>, source::Error> {
+        self.artists
+            .iter()
+            .flat_map(|a| &a.albums)
+            .find(|a| a.info.id == album.id)
+            .map(|a| a.tracks.clone())
+            .ok_or(source::Error::NotFound)
+    }
+
+    async fn preview_url(&self, track: i64) -> Result<String, source::Error> {
+        if !self.paths.contains_key(&track) {
+            return Err(source::Error::NotFound);
+        }
+        Ok(format!("/library/tracks/{track}"))
+    }
+
+    fn local_path(&self, track: i64) -> Option<PathBuf> {
+        self.paths.get(&track).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_files::flac_file;
+
+    #[tokio::test]
+    async fn test_scan() -> Result<(), source::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("album")).unwrap();
+        fs::write(
+            dir.path().join("album/01.flac"),
+            flac_file(
+                &[
+                    "ARTIST=Local Band",
+                    "ALBUM=Demos",
+                    "TITLE=First Demo",
+                    "TRACKNUMBER=1/2",
+                    "DATE=2011",
+                    "ISRC=XX0000000001",
+                ],
+                1,
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("album/02.flac"),
+            flac_file(&["ARTIST=Local Band", "ALBUM=Demos"], 1),
+        )
+        .unwrap();
+        fs::write(dir.path().join("notes.txt"), "not audio").unwrap();
+
+        let library = LibrarySource::scan(dir.path()).unwrap();
+        let artists = library.search_artists("local", 0, 10).await?;
+        assert_eq!(artists.len(), 1);
+        assert_eq!(artists[0].title, "Local Band");
+
+        let albums = library.artist_albums(artists[0].id as u32).await?;
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].title, "Demos");
+
+        let mut titles: Vec<_> = library
+            .album_tracks(&albums[0])
+            .await?
+            .into_iter()
+            .map(|t| t.title)
+            .collect();
+        titles.sort();
+        assert_eq!(titles, ["02", "First Demo"]);
+
+        let track = library.album_tracks(&albums[0]).await?.remove(0);
+        assert_eq!(track.track_position, Some(1));
+        assert_eq!(track.isrc.as_deref(), Some("XX0000000001"));
+        assert_eq!(
+            track.album_release_date,
+            NaiveDate::from_ymd_opt(2011, 1, 1)
+        );
+        assert_eq!(track.duration, Some(0));
+        assert_eq!(
+            library.local_path(track.id).unwrap(),
+            dir.path().join("album/01.flac")
+        );
+        assert_eq!(
+            library.preview_url(track.id).await?,
+            format!("/library/tracks/{}", track.id)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(parse_number("3"), Some(3));
+        assert_eq!(parse_number(" 3/12"), Some(3));
+        assert_eq!(parse_number("A1"), None);
+        assert_eq!(
+            parse_date("2011-05-03"),
+            NaiveDate::from_ymd_opt(2011, 5, 3)
+        );
+        assert_eq!(
+            parse_date("2011-05-03T12:00:00"),
+            NaiveDate::from_ymd_opt(2011, 5, 3)
+        );
+        assert_eq!(parse_date("2011"), NaiveDate::from_ymd_opt(2011, 1, 1));
+        assert_eq!(parse_date("unknown"), None);
+    }
+
+    #[test]
+    fn test_stable_id() {
+        assert_eq!(stable_id("Local Band", 0), stable_id("Local Band", 0));
+        assert_ne!(stable_id("Local Band", 0), stable_id("Local Bands", 0));
+        assert_ne!(stable_id("Local Band", 0), stable_id("Local Band", 1));
+        assert!(stable_id("Local Band", 0) <= i64::from(i32::MAX));
+    }
+
+    #[test]
+    fn test_id_collisions() {
+        // these two hash to the same id
+        let (a, b) = ("track381689.flac", "track528934.flac");
+        assert_eq!(stable_id(a, 0), stable_id(b, 0));
+
+        let keys = |keys: &[&str]| Ids::new(keys.iter().map(|&k| k.to_owned()));
+        let ids = keys(&[b, "other.flac", a]);
+        assert_eq!(ids.get(a), stable_id(a, 0));
+        assert_eq!(ids.get(b), stable_id(b, 1));
+        assert_eq!(ids.get("other.flac"), stable_id("other.flac", 0));
+
+        // the order keys come in doesn't matter, nor do keys that don't collide
+        let reordered = keys(&[a, b]);
+        assert_eq!(reordered.get(a), ids.get(a));
+        assert_eq!(reordered.get(b), ids.get(b));
+        assert_eq!(
+            keys(&["other.flac"]).get("other.flac"),
+            ids.get("other.flac")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_symlink_loop() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("album")).unwrap();
+        fs::write(
+            dir.path().join("album/01.flac"),
+            flac_file(&["ARTIST=Local Band", "TITLE=Song"], 1),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("album/loop")).unwrap();
+
+        let library = LibrarySource::scan(dir.path()).unwrap();
+        assert_eq!(library.paths.len(), 1);
+    }
+}
@@ -0,0 +1,464 @@
+//! Cutting preview clips out of library files. Only the start of the audio is kept,
+//! and none of the tags, so that a preview doesn't give away the answer.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+    time::Duration,
+};
+
+use symphonia::core::{
+    errors::Error as DecodeError, formats::FormatOptions, io::MediaSourceStream,
+    meta::MetadataOptions, probe::Hint,
+};
+use thiserror::Error;
+
+/// How long the previews of library tracks are, like the ones Deezer serves.
+pub const CLIP_LENGTH: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum ClipError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+    #[error("decoding error")]
+    DecodeError(#[from] DecodeError),
+    #[error("unsupported format")]
+    Unsupported,
+}
+
+/// A preview clip, ready to be served.
+#[derive(Debug)]
+pub struct Clip {
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// Cuts the first `length` of the audio file at `path` into a clip without tags.
+pub fn preview_clip(path: &Path, length: Duration) -> Result<Clip, ClipError> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase);
+    match ext.as_deref() {
+        Some("mp3") => Ok(Clip {
+            content_type: "audio/mpeg",
+            data: mp3_clip(path, length)?,
+        }),
+        Some("flac") => Ok(Clip {
+            content_type: "audio/flac",
+            data: flac_clip(path, length)?,
+        }),
+        Some("ogg" | "oga") => Ok(Clip {
+            content_type: "audio/ogg",
+            data: ogg_clip(path, length)?,
+        }),
+        _ => Err(ClipError::Unsupported),
+    }
+}
+
+/// Appends the packets of the first `length` of the file at `path` to `out`. Tags
+/// are read separately from the packets, so they never end up in `out`.
+fn copy_packets(path: &Path, length: Duration, out: &mut Vec<u8>) -> Result<(), ClipError> {
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mss = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format.default_track().ok_or(ClipError::Unsupported)?;
+    let (track_id, time_base) = (track.id, track.codec_params.time_base);
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            Err(DecodeError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        if let Some(time_base) = time_base {
+            let time = time_base.calc_time(packet.ts());
+            if time.seconds as f64 + time.frac >= length.as_secs_f64() {
+                break;
+            }
+        }
+        out.extend_from_slice(packet.buf());
+    }
+    Ok(())
+}
+
+/// MP3 frames carry no tags, so the clip is just the first frames of the file.
+fn mp3_clip(path: &Path, length: Duration) -> Result<Vec<u8>, ClipError> {
+    let mut out = Vec::new();
+    copy_packets(path, length, &mut out)?;
+    Ok(out)
+}
+
+/// Length of the STREAMINFO metadata block, the only one a FLAC file needs.
+const STREAMINFO_LEN: usize = 34;
+
+/// Keeps the STREAMINFO block of a FLAC file, dropping all other metadata blocks
+/// (Vorbis comments, pictures, ...), followed by the first frames.
+fn flac_clip(path: &Path, length: Duration) -> Result<Vec<u8>, ClipError> {
+    let mut header = [0; 8 + STREAMINFO_LEN];
+    File::open(path)?.read_exact(&mut header)?;
+    // STREAMINFO has to come first, right after the marker
+    if &header[..4] != b"fLaC" || header[4] & 0x7f != 0 {
+        return Err(ClipError::Unsupported);
+    }
+
+    let mut streaminfo = header[8..].to_vec();
+    // the total number of samples and the MD5 sum of the audio no longer hold
+    streaminfo[13] &= 0xf0;
+    streaminfo[14..].fill(0);
+
+    let mut out = b"fLaC".to_vec();
+    out.push(0x80); // STREAMINFO, last
+    out.extend(&(STREAMINFO_LEN as u32).to_be_bytes()[1..]);
+    out.extend(streaminfo);
+    copy_packets(path, length, &mut out)?;
+    Ok(out)
+}
+
+/// A page of an Ogg stream.
+struct OggPage {
+    flags: u8,
+    granule: u64,
+    serial: u32,
+    lacing: Vec<u8>,
+    body: Vec<u8>,
+}
+
+const OGG_CONTINUED: u8 = 0x01;
+const OGG_FIRST: u8 = 0x02;
+const OGG_LAST: u8 = 0x04;
+
+/// The granule position of pages on which no packet ends.
+const OGG_NO_GRANULE: u64 = u64::MAX;
+
+impl OggPage {
+    /// Reads the next page from `reader`, or `None` at the end of the stream.
+    fn read(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut header = [0; 27];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        if &header[..4] != b"OggS" || header[4] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an Ogg page",
+            ));
+        }
+        let mut lacing = vec![0; usize::from(header[26])];
+        reader.read_exact(&mut lacing)?;
+        let mut body = vec![0; lacing.iter().map(|&l| usize::from(l)).sum()];
+        reader.read_exact(&mut body)?;
+        Ok(Some(Self {
+            flags: header[5],
+            granule: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+            serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+            lacing,
+            body,
+        }))
+    }
+
+    /// Appends the page to `out` as the `seq`th page of its stream.
+    fn write(&self, seq: u32, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend(b"OggS\0");
+        out.push(self.flags);
+        out.extend(self.granule.to_le_bytes());
+        out.extend(self.serial.to_le_bytes());
+        out.extend(seq.to_le_bytes());
+        out.extend([0; 4]); // checksum, filled in below
+        out.push(self.lacing.len() as u8);
+        out.extend(&self.lacing);
+        out.extend(&self.body);
+        let crc = ogg_crc(&out[start..]);
+        out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Splits `packets` into as many pages as they need.
+    fn from_packets(serial: u32, flags: u8, packets: &[Vec<u8>]) -> Vec<Self> {
+        let mut pages = Vec::new();
+        let mut page = Self {
+            flags,
+            granule: OGG_NO_GRANULE,
+            serial,
+            lacing: Vec::new(),
+            body: Vec::new(),
+        };
+        for packet in packets {
+            let mut rest = &packet[..];
+            loop {
+                if page.lacing.len() == 255 {
+                    let continued = page.lacing.last() == Some(&255);
+                    pages.push(page);
+                    page = Self {
+                        flags: if continued { OGG_CONTINUED } else { 0 },
+                        granule: OGG_NO_GRANULE,
+                        serial,
+                        lacing: Vec::new(),
+                        body: Vec::new(),
+                    };
+                }
+                let len = rest.len().min(255);
+                page.lacing.push(len as u8);
+                page.body.extend(&rest[..len]);
+                rest = &rest[len..];
+                if len < 255 {
+                    // header packets all belong before the first sample
+                    page.granule = 0;
+                    break;
+                }
+            }
+        }
+        pages.push(page);
+        pages
+    }
+}
+
+/// Computes the checksum of an Ogg page.
+pub(super) fn ogg_crc(page: &[u8]) -> u32 {
+    page.iter().fold(0u32, |mut crc, &b| {
+        crc ^= u32::from(b) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// Rebuilds the headers of the first logical stream of an Ogg file with an empty
+/// comment header, followed by the pages holding its first `length` of audio.
+/// Vorbis and Opus streams are supported.
+fn ogg_clip(path: &Path, length: Duration) -> Result<Vec<u8>, ClipError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let first = OggPage::read(&mut reader)?.ok_or(ClipError::Unsupported)?;
+    let serial = first.serial;
+
+    // the identification header has the first page to itself
+    if first.lacing.len() != 1 {
+        return Err(ClipError::Unsupported);
+    }
+    let ident = first.body;
+    let (header_count, sample_rate, comment): (_, u64, &[u8]) =
+        if ident.starts_with(b"\x01vorbis") && ident.len() >= 16 {
+            let rate = u32::from_le_bytes(ident[12..16].try_into().unwrap());
+            (3, rate.into(), b"\x03vorbis\0\0\0\0\0\0\0\0\x01")
+        } else if ident.starts_with(b"OpusHead") {
+            (2, 48_000, b"OpusTags\0\0\0\0\0\0\0\0")
+        } else {
+            return Err(ClipError::Unsupported);
+        };
+
+    let mut headers = vec![ident, comment.to_vec()];
+    let mut packet = Vec::new();
+    let mut seen = 1;
+    while seen < header_count {
+        let page = OggPage::read(&mut reader)?.ok_or(ClipError::Unsupported)?;
+        if page.serial != serial {
+            continue;
+        }
+        let mut body = &page.body[..];
+        for &len in &page.lacing {
+            packet.extend(&body[..usize::from(len)]);
+            body = &body[usize::from(len)..];
+            if len < 255 {
+                let header = std::mem::take(&mut packet);
+                // the comment header is replaced, the ones after it are kept
+                if seen > 1 {
+                    headers.push(header);
+                }
+                seen += 1;
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut seq = 0;
+    let mut pages = OggPage::from_packets(serial, OGG_FIRST, &headers[..1]);
+    pages.extend(OggPage::from_packets(serial, 0, &headers[1..]));
+    for page in pages {
+        page.write(seq, &mut out);
+        seq += 1;
+    }
+
+    // audio starts on a fresh page, so the rest can be copied page by page
+    let end = (length.as_secs_f64() * sample_rate as f64) as u64;
+    while let Some(mut page) = OggPage::read(&mut reader)? {
+        if page.serial != serial {
+            continue;
+        }
+        let done =
+            page.flags & OGG_LAST != 0 || (page.granule != OGG_NO_GRANULE && page.granule >= end);
+        if done {
+            page.flags |= OGG_LAST;
+        }
+        page.write(seq, &mut out);
+        seq += 1;
+        if done {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::test_files::{flac_file, mp3_file, ogg_file};
+    use super::*;
+
+    const TITLE: &str = "Secret Title";
+
+    fn contains(data: &[u8], needle: &str) -> bool {
+        data.windows(needle.len()).any(|w| w == needle.as_bytes())
+    }
+
+    /// Writes `data` to a file named `name` and cuts the first `length` out of it.
+    fn clip(name: &str, data: &[u8], length: Duration) -> Clip {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::write(&path, data).unwrap();
+        preview_clip(&path, length).unwrap()
+    }
+
+    /// Counts the packets of the audio file `data` with the extension `ext`, and
+    /// checks that none of its tags are left.
+    fn count_packets(ext: &str, data: Vec<u8>) -> usize {
+        let mut hint = Hint::new();
+        hint.with_extension(ext);
+        let mss = MediaSourceStream::new(Box::new(io::Cursor::new(data)), Default::default());
+        let mut probed = symphonia::default::get_probe()
+            .format(
+                &hint,
+                mss,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap();
+        assert!(probed
+            .format
+            .metadata()
+            .current()
+            .is_none_or(|rev| rev.tags().is_empty()));
+        assert!(probed
+            .metadata
+            .get()
+            .and_then(|m| m.current().cloned())
+            .is_none());
+
+        let mut count = 0;
+        while probed.format.next_packet().is_ok() {
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn test_flac_clip() {
+        let file = flac_file(&[&format!("TITLE={TITLE}"), "ARTIST=Band"], 10);
+        let clip = clip("track.flac", &file, Duration::from_millis(10));
+        assert_eq!(clip.content_type, "audio/flac");
+        assert!(!contains(&clip.data, TITLE));
+        assert!(!contains(&clip.data, "Band"));
+        // frames start every 4.35 ms
+        assert_eq!(count_packets("flac", clip.data), 3);
+    }
+
+    #[test]
+    fn test_mp3_clip() {
+        let file = mp3_file(TITLE, 100);
+        let clip = clip("track.mp3", &file, Duration::from_millis(100));
+        assert_eq!(clip.content_type, "audio/mpeg");
+        assert!(!contains(&clip.data, TITLE));
+        // frames start every 26.1 ms
+        assert_eq!(count_packets("mp3", clip.data), 4);
+
+        // the ID3v1 tag at the very end is left out too
+        let clip = self::clip("track.mp3", &mp3_file(TITLE, 3), CLIP_LENGTH);
+        assert!(!contains(&clip.data, TITLE));
+        assert_eq!(clip.data.len(), 3 * 417);
+    }
+
+    #[test]
+    fn test_ogg_clip() {
+        let file = ogg_file(TITLE, 10);
+        let clip = clip("track.ogg", &file, Duration::from_secs(3));
+        assert_eq!(clip.content_type, "audio/ogg");
+        assert!(!contains(&clip.data, TITLE));
+        assert!(contains(&clip.data, "\x05vorbis setup"));
+
+        let mut reader = &clip.data[..];
+        let mut pages = Vec::new();
+        while let Some(page) = OggPage::read(&mut reader).unwrap() {
+            pages.push(page);
+        }
+        // the headers, then the pages up to 3 s, the last one marked as such
+        assert_eq!(pages.len(), 2 + 3);
+        assert_eq!(pages[0].flags, OGG_FIRST);
+        assert_eq!(pages.last().unwrap().flags, OGG_LAST);
+        assert_eq!(pages.last().unwrap().granule, 3 * 44100);
+
+        // every page has a valid checksum and its own sequence number
+        let mut rest = &clip.data[..];
+        for seq in 0..pages.len() as u32 {
+            let len = 27
+                + usize::from(rest[26])
+                + rest[27..27 + usize::from(rest[26])]
+                    .iter()
+                    .map(|&l| usize::from(l))
+                    .sum::<usize>();
+            let mut page = rest[..len].to_vec();
+            assert_eq!(u32::from_le_bytes(page[18..22].try_into().unwrap()), seq);
+            let crc = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].fill(0);
+            assert_eq!(ogg_crc(&page), crc);
+            rest = &rest[len..];
+        }
+    }
+
+    #[test]
+    fn test_ogg_long_header() {
+        // headers longer than a page's 255 segments are split across pages
+        let mut packets = vec![b"\x05vorbis".to_vec()];
+        packets[0].resize(255 * 300, 7);
+        let pages = OggPage::from_packets(1, 0, &packets);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].granule, OGG_NO_GRANULE);
+        assert_eq!(pages[1].flags, OGG_CONTINUED);
+        assert_eq!(pages[1].granule, 0);
+        // a packet that's a multiple of 255 bytes long ends with an empty segment
+        assert_eq!(pages[1].lacing.last(), Some(&0));
+        let body: usize = pages.iter().map(|p| p.body.len()).sum();
+        assert_eq!(body, 255 * 300);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("track.wav");
+        fs::write(&path, b"RIFF").unwrap();
+        assert!(matches!(
+            preview_clip(&path, CLIP_LENGTH),
+            Err(ClipError::Unsupported)
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+//! Builds minimal audio files with the given tags, for tests.
+
+use super::clip::ogg_crc;
+
+fn crc(data: &[u8], poly: u16, width: u32) -> u16 {
+    let top = 1 << (width - 1);
+    let mask = ((1u32 << width) - 1) as u16;
+    data.iter().fold(0u16, |mut crc, &b| {
+        crc ^= u16::from(b) << (width - 8);
+        for _ in 0..8 {
+            crc = if crc & top != 0 {
+                (crc << 1) ^ poly
+            } else {
+                crc << 1
+            };
+        }
+        crc & mask
+    })
+}
+
+/// Builds a FLAC file with the given Vorbis comments and `frames` silent frames
+/// of 192 samples at 44.1 kHz each.
+pub fn flac_file(comments: &[&str], frames: u8) -> Vec<u8> {
+    assert!(frames < 0x80, "frame numbers are written as a single byte");
+    let mut out = b"fLaC".to_vec();
+
+    let mut streaminfo = Vec::new();
+    streaminfo.extend(192u16.to_be_bytes()); // min block size
+    streaminfo.extend(192u16.to_be_bytes()); // max block size
+    streaminfo.extend([0; 6]); // min/max frame size
+
+    // 44100 Hz, 2 channels, 16 bits per sample
+    let packed: u64 = (44100 << 44) | (1 << 41) | (15 << 36) | (192 * u64::from(frames));
+    streaminfo.extend(packed.to_be_bytes());
+    streaminfo.extend([0; 16]); // md5
+    out.push(0); // STREAMINFO, not last
+    out.extend(&(streaminfo.len() as u32).to_be_bytes()[1..]);
+    out.extend(streaminfo);
+
+    let mut vorbis = Vec::new();
+    let vendor = b"test";
+    vorbis.extend((vendor.len() as u32).to_le_bytes());
+    vorbis.extend(vendor);
+    vorbis.extend((comments.len() as u32).to_le_bytes());
+    for c in comments {
+        vorbis.extend((c.len() as u32).to_le_bytes());
+        vorbis.extend(c.as_bytes());
+    }
+    out.push(0x80 | 4); // VORBIS_COMMENT, last
+    out.extend(&(vorbis.len() as u32).to_be_bytes()[1..]);
+    out.extend(vorbis);
+
+    for number in 0..frames {
+        // 192 samples at 44.1 kHz, independent stereo, 16 bits per sample
+        let mut frame = vec![0xff, 0xf8, 0x19, 0x18, number];
+        frame.push(crc(&frame, 0x07, 8) as u8);
+        frame.extend([0; 6]); // two constant subframes of silence
+        frame.extend(crc(&frame, 0x8005, 16).to_be_bytes());
+        out.extend(frame);
+    }
+    out
+}
+
+/// Builds an MP3 file with `title` in both an ID3v2 and an ID3v1 tag, and
+/// `frames` empty frames of 1152 samples at 44.1 kHz each.
+pub fn mp3_file(title: &str, frames: usize) -> Vec<u8> {
+    let mut tit2 = vec![0]; // ISO-8859-1
+    tit2.extend(title.as_bytes());
+    let mut id3 = b"TIT2".to_vec();
+    id3.extend((tit2.len() as u32).to_be_bytes());
+    id3.extend([0, 0]); // flags
+    id3.extend(tit2);
+
+    let mut out = b"ID3\x03\x00\x00".to_vec();
+    // syncsafe size, 7 bits per byte
+    let size = id3.len() as u32;
+    out.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+    out.extend(id3);
+
+    for _ in 0..frames {
+        // MPEG-1 layer III, 128 kbit/s, 44.1 kHz, joint stereo: 417 bytes
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x64];
+        frame.resize(417, 0);
+        out.extend(frame);
+    }
+
+    let mut id3v1 = b"TAG".to_vec();
+    id3v1.extend(title.as_bytes());
+    id3v1.resize(128, 0);
+    out.extend(id3v1);
+    out
+}
+
+/// Builds an Ogg page holding the whole of each of `packets`, which must be
+/// shorter than 255 bytes.
+fn ogg_page(flags: u8, granule: u64, seq: u32, packets: &[&[u8]]) -> Vec<u8> {
+    let mut page = b"OggS\0".to_vec();
+    page.push(flags);
+    page.extend(granule.to_le_bytes());
+    page.extend(1u32.to_le_bytes()); // serial
+    page.extend(seq.to_le_bytes());
+    page.extend([0; 4]);
+    page.push(packets.len() as u8);
+    page.extend(packets.iter().map(|p| p.len() as u8));
+    for packet in packets {
+        page.extend(*packet);
+    }
+    let crc = ogg_crc(&page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+/// Builds an Ogg Vorbis file with `title` in its comment header, followed by
+/// `seconds` pages of a second's worth of made-up audio each. The headers are
+/// only as complete as needed to tell the stream apart.
+pub fn ogg_file(title: &str, seconds: u64) -> Vec<u8> {
+    let mut ident = b"\x01vorbis\0\0\0\0\x02".to_vec();
+    ident.extend(44100u32.to_le_bytes());
+    ident.resize(30, 0);
+
+    let mut comment = b"\x03vorbis".to_vec();
+    comment.extend(0u32.to_le_bytes()); // vendor
+    comment.extend(1u32.to_le_bytes());
+    let tag = format!("TITLE={title}");
+    comment.extend((tag.len() as u32).to_le_bytes());
+    comment.extend(tag.as_bytes());
+    comment.push(1);
+
+    let setup = b"\x05vorbis setup".to_vec();
+
+    let mut out = ogg_page(0x02, 0, 0, &[&ident]);
+    out.extend(ogg_page(0, 0, 1, &[&comment, &setup]));
+    for second in 1..=seconds {
+        let flags = if second == seconds { 0x04 } else { 0 };
+        let audio = [second as u8; 100];
+        out.extend(ogg_page(
+            flags,
+            44100 * second,
+            second as u32 + 1,
+            &[&audio],
+        ));
+    }
+    out
+}
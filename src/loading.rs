@@ -2,58 +2,103 @@ use std::{
     collections::HashMap,
     future::Future,
     hash::Hash,
-    sync::{Arc, RwLock, Weak},
+    sync::{Arc, PoisonError, RwLock, Weak},
+    thread,
+    time::Duration,
 };
 
-use tokio::sync::broadcast;
+use thiserror::Error;
+use tokio::sync::{broadcast, watch};
 
-struct LoadingGuard<I, T>
+/// Why a load didn't produce a value.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum LoadingError {
+    #[error("loading panicked")]
+    Panicked,
+    #[error("loading was cancelled")]
+    Aborted,
+    #[error("loading timed out")]
+    TimedOut,
+}
+
+/// A load in progress: where its result will be sent, and how far along it is.
+struct Entry<T, P> {
+    result: broadcast::Sender<Result<T, LoadingError>>,
+    progress: Progress<P>,
+}
+
+type Entries<I, T, P> = RwLock<HashMap<I, Entry<T, P>>>;
+
+/// Lets a running load report how far along it is, see [`Loading::progress`].
+pub struct Progress<P>(Arc<watch::Sender<P>>);
+
+impl<P> Progress<P> {
+    /// Updates the progress in place, notifying everyone watching it.
+    pub fn update(&self, f: impl FnOnce(&mut P)) {
+        self.0.send_modify(f);
+    }
+}
+
+impl<P> Clone for Progress<P> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+struct LoadingGuard<I, T, P>
 where
     I: Eq + Hash + Clone + Send + Sync + 'static,
     T: Clone + Send + 'static,
 {
     id: I,
-    futweakref: Weak<RwLock<HashMap<I, broadcast::Sender<T>>>>,
+    futweakref: Weak<Entries<I, T, P>>,
 }
 
-impl<I, T> LoadingGuard<I, T>
+impl<I, T, P> LoadingGuard<I, T, P>
 where
     I: Eq + Hash + Clone + Send + Sync + 'static,
     T: Clone + Send + 'static,
 {
-    pub fn new(id: I, ld: &Loading<I, T>) -> Self {
+    pub fn new(id: I, ld: &Loading<I, T, P>) -> Self {
         Self {
             id,
             futweakref: Arc::downgrade(&ld.futures),
         }
     }
 
-    fn sender(&mut self) -> Option<broadcast::Sender<T>> {
-        let retv = self
-            .futweakref
+    /// Takes the entry of the load out of the `Loading`, if it's still there. This
+    /// is also called while unwinding, so it must not panic.
+    fn sender(&mut self) -> Option<broadcast::Sender<Result<T, LoadingError>>> {
+        let entry = std::mem::take(&mut self.futweakref)
             .upgrade()?
             .write()
-            .expect("poisoned mutex")
-            .remove(&self.id)
-            .expect("weird race condition");
-        self.futweakref = Weak::new();
-        Some(retv)
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.id)?;
+        Some(entry.result)
     }
 
-    pub fn resolve(mut self, val: T) {
+    pub fn resolve(mut self, val: Result<T, LoadingError>) {
         if let Some(sender) = self.sender() {
             _ = sender.send(val);
         }
     }
 }
 
-impl<I, T> Drop for LoadingGuard<I, T>
+impl<I, T, P> Drop for LoadingGuard<I, T, P>
 where
     I: Eq + Hash + Clone + Send + Sync + 'static,
     T: Clone + Send + 'static,
 {
+    /// Only runs without resolving when the task was dropped before finishing,
+    /// which happens either when it panics or when it's aborted.
     fn drop(&mut self) {
-        drop(self.sender());
+        if let Some(sender) = self.sender() {
+            _ = sender.send(Err(if thread::panicking() {
+                LoadingError::Panicked
+            } else {
+                LoadingError::Aborted
+            }));
+        }
     }
 }
 
@@ -65,14 +110,18 @@ where
 /// The `Loading` struct helps solve the latter issue by only running
 /// one task at a time for each I, reusing existing loads when
 /// necessary.
-pub struct Loading<I, T> {
-    futures: Arc<RwLock<HashMap<I, broadcast::Sender<T>>>>,
+///
+/// Loads can also report their progress as a `P`, which can be watched while
+/// they are running, and each can be given its own timeout.
+pub struct Loading<I, T, P = ()> {
+    futures: Arc<Entries<I, T, P>>,
 }
 
-impl<I, T> Loading<I, T>
+impl<I, T, P> Loading<I, T, P>
 where
     I: Eq + Hash + Clone + Send + Sync + 'static,
     T: Clone + Send + 'static,
+    P: Default + Send + Sync + 'static,
 {
     pub fn new() -> Self {
         Self {
@@ -82,29 +131,129 @@ where
 
     /// If loading isn't in progress, runs `fut` and registers it as loading `I`.
     /// Else, waits for the currently active loading to finish and returns a cloned value.
-    pub async fn run<F>(&self, id: I, fut: F) -> T
+    pub async fn run<F>(&self, id: I, fut: F) -> Result<T, LoadingError>
     where
         F: Future<Output = T> + Send + 'static,
     {
-        let running_rx = self.futures.read().unwrap().get(&id).map(|s| s.subscribe());
-        if let Some(mut rrx) = running_rx {
-            return rrx.recv().await.unwrap();
+        self.run_with_progress(id, None, |_| fut).await
+    }
+
+    /// Like [`Loading::run`], but the future is created by `make`, which is given
+    /// a handle to report the progress of the load with. If the load is started
+    /// here, it fails with [`LoadingError::TimedOut`] once it takes longer than
+    /// `timeout`.
+    pub async fn run_with_progress<F>(
+        &self,
+        id: I,
+        timeout: Option<Duration>,
+        make: impl FnOnce(Progress<P>) -> F,
+    ) -> Result<T, LoadingError>
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let mut rx = match self.register(id.clone()) {
+            Ok((progress, rx)) => {
+                self.start(id, timeout, make(progress));
+                rx
+            }
+            Err(running_rx) => running_rx,
+        };
+        // the guard always sends a result, unless the whole `Loading` is gone
+        rx.recv().await.unwrap_or(Err(LoadingError::Aborted))
+    }
+
+    /// Starts running `fut` in the background as loading `I`, unless loading `I`
+    /// is already in progress. Returns whether `fut` was started.
+    pub fn spawn<F>(&self, id: I, fut: F) -> bool
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        self.spawn_with_progress(id, None, |_| fut)
+    }
+
+    /// Like [`Loading::spawn`], but the future is created by `make`, which is given
+    /// a handle to report the progress of the load with, and the load fails with
+    /// [`LoadingError::TimedOut`] once it takes longer than `timeout`.
+    pub fn spawn_with_progress<F>(
+        &self,
+        id: I,
+        timeout: Option<Duration>,
+        make: impl FnOnce(Progress<P>) -> F,
+    ) -> bool
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        match self.register(id.clone()) {
+            Ok((progress, _)) => {
+                self.start(id, timeout, make(progress));
+                true
+            }
+            Err(_) => false,
         }
-        let (tx, mut rx) = broadcast::channel(1);
-        self.futures.write().unwrap().insert(id.clone(), tx);
+    }
+
+    /// Whether loading `id` is in progress.
+    pub fn is_loading(&self, id: &I) -> bool {
+        self.futures.read().unwrap().contains_key(id)
+    }
+
+    /// Watches the progress of loading `id`, if it is in progress. The watch is
+    /// closed once the load has finished.
+    pub fn progress(&self, id: &I) -> Option<watch::Receiver<P>> {
+        self.futures
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|e| e.progress.0.subscribe())
+    }
+
+    /// Registers `id` as loading, unless it already is, in which case this
+    /// subscribes to the result of the running load instead.
+    #[allow(clippy::type_complexity)]
+    fn register(
+        &self,
+        id: I,
+    ) -> Result<
+        (Progress<P>, broadcast::Receiver<Result<T, LoadingError>>),
+        broadcast::Receiver<Result<T, LoadingError>>,
+    > {
+        let mut futures = self.futures.write().unwrap();
+        if let Some(running) = futures.get(&id) {
+            return Err(running.result.subscribe());
+        }
+        let (tx, rx) = broadcast::channel(1);
+        let progress = Progress(Arc::new(watch::channel(P::default()).0));
+        let entry = Entry {
+            result: tx,
+            progress: progress.clone(),
+        };
+        futures.insert(id, entry);
+        Ok((progress, rx))
+    }
+
+    fn start<F>(&self, id: I, timeout: Option<Duration>, fut: F)
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
         let futguard = LoadingGuard::new(id, self);
         tokio::spawn(async move {
             let futguard = futguard;
-            futguard.resolve(fut.await);
+            let result = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, fut)
+                    .await
+                    .map_err(|_| LoadingError::TimedOut),
+                None => Ok(fut.await),
+            };
+            futguard.resolve(result);
         });
-        rx.recv().await.unwrap()
     }
 }
 
-impl<I, T> Default for Loading<I, T>
+impl<I, T, P> Default for Loading<I, T, P>
 where
     I: Eq + Hash + Clone + Send + Sync + 'static,
     T: Clone + Send + 'static,
+    P: Default + Send + Sync + 'static,
 {
     fn default() -> Self {
         Self::new()
@@ -122,7 +271,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_loading() {
-        let loading = Loading::new();
+        let loading: Loading<_, _> = Loading::new();
         let num = Arc::new(AtomicU32::new(0));
         async fn f(x: Arc<AtomicU32>) -> u32 {
             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -134,8 +283,8 @@ mod tests {
             loading.run("meow", f(Arc::clone(&num)))
         );
 
-        assert_eq!(r1, 0);
-        assert_eq!(r2, 0);
+        assert_eq!(r1, Ok(0));
+        assert_eq!(r2, Ok(0));
 
         tokio::time::sleep(Duration::from_secs(2)).await;
 
@@ -144,7 +293,82 @@ mod tests {
             loading.run("meow", f(Arc::clone(&num)))
         );
 
-        assert_eq!(r1, 1);
-        assert_eq!(r2, 1);
+        assert_eq!(r1, Ok(1));
+        assert_eq!(r2, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_spawn() {
+        let loading: Loading<_, _> = Loading::new();
+        async fn f(x: u32) -> u32 {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            x
+        }
+
+        assert!(loading.spawn("meow", f(1)));
+        assert!(!loading.spawn("meow", f(2)));
+        // joins the load started in the background
+        assert_eq!(loading.run("meow", f(3)).await, Ok(1));
+
+        assert!(loading.spawn("meow", f(4)));
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let loading: Loading<&str, u32, u32> = Loading::new();
+        let (step_tx, mut step_rx) = tokio::sync::mpsc::channel::<()>(1);
+        assert!(
+            loading.spawn_with_progress("meow", None, |progress| async move {
+                for _ in 0..3 {
+                    step_rx.recv().await;
+                    progress.update(|p| *p += 1);
+                }
+                3
+            })
+        );
+
+        let mut watch = loading.progress(&"meow").unwrap();
+        assert_eq!(*watch.borrow_and_update(), 0);
+        for expected in 1..=3 {
+            step_tx.send(()).await.unwrap();
+            watch.changed().await.unwrap();
+            assert_eq!(*watch.borrow_and_update(), expected);
+        }
+
+        // the watch is closed once the load is done
+        assert!(watch.changed().await.is_err());
+        assert!(loading.progress(&"meow").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let loading: Loading<_, _> = Loading::new();
+        let slow = |_| tokio::time::sleep(Duration::from_millis(300));
+        let (short, long) = tokio::join!(
+            loading.run_with_progress("meow", Some(Duration::from_millis(100)), slow),
+            loading.run_with_progress("purr", Some(Duration::from_secs(10)), slow)
+        );
+        assert_eq!(short, Err(LoadingError::TimedOut));
+        assert_eq!(long, Ok(()));
+        assert!(!loading.is_loading(&"meow"));
+        assert_eq!(loading.run("meow", async {}).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_guard_without_entry() {
+        let loading: Loading<_, ()> = Loading::new();
+        // the entry is gone, e.g. because the load already resolved
+        drop(LoadingGuard::new("meow", &loading));
+        let guard = LoadingGuard::new("meow", &loading);
+        drop(loading);
+        drop(guard);
+    }
+
+    #[tokio::test]
+    async fn test_panic() {
+        let loading: Loading<_, u32> = Loading::new();
+        let result = loading.run("meow", async { panic!("meow") }).await;
+        assert_eq!(result, Err(LoadingError::Panicked));
+        assert!(!loading.is_loading(&"meow"));
     }
 }
@@ -1,8 +1,10 @@
 use std::env;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
 
+use music_quiz::{gc, refresher};
+
 #[actix_web::main]
 pub async fn main() -> Result<(), Box<dyn Error>> {
     let mut args = env::args().skip(1);
@@ -22,8 +24,35 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
             let pool = sqlx::PgPool::connect_lazy(&config.database_url)?;
             sqlx::migrate!().run(&pool).await?;
         }
+        "warm" => {
+            // each argument is either an artist id or a file listing them
+            let mut artists = Vec::new();
+            for arg in args {
+                match arg.parse() {
+                    Ok(id) => artists.push(id),
+                    Err(_) => {
+                        artists.extend(refresher::parse_artist_ids(&fs::read_to_string(arg)?)?)
+                    }
+                }
+            }
+
+            let state = music_quiz::routing::build_state(&config)?;
+            let failed = refresher::warm(&state, artists).await;
+            if failed > 0 {
+                return Err(format!("{failed} artists could not be cached").into());
+            }
+        }
+        "gc" => {
+            let dry_run = match args.next().as_deref() {
+                None => false,
+                Some("--dry-run") => true,
+                Some(arg) => panic!("invalid argument to gc: {arg}"),
+            };
+            let state = music_quiz::routing::build_state(&config)?;
+            gc::collect(&state, &config.gc, dry_run).await?;
+        }
         _ => {
-            panic!("invalid command: must be either start or migrate");
+            panic!("invalid command: must be one of start, migrate, warm or gc");
         }
     }
 
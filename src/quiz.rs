@@ -0,0 +1,770 @@
+use std::sync::Arc;
+
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use thiserror::Error;
+
+use crate::db::{QuizAnswerInfo, QuizSessionInfo, TrackInfo};
+use crate::difficulty::Difficulty;
+use crate::distractors::DistractorStrategy;
+use crate::titles::TitleNormalizer;
+
+/// The maximum length of a name submitted to the leaderboard, in characters.
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("no such quiz session")]
+    NotFound,
+    #[error("no such question in this session")]
+    UnknownQuestion,
+    #[error("question already answered")]
+    AlreadyAnswered,
+    #[error("question answered before being asked")]
+    NotAsked,
+    #[error("the quiz is already over")]
+    AlreadyFinished,
+    #[error("the answer track is no longer available")]
+    TrackMissing,
+    #[error("the quiz has not been finished yet")]
+    NotFinished,
+    #[error("a name has already been submitted for this session")]
+    AlreadySubmitted,
+    #[error("invalid player name")]
+    InvalidName,
+    #[error("no question has been answered yet")]
+    NothingAnswered,
+    #[error("database error")]
+    DbError(#[from] sqlx::Error),
+}
+
+/// A question as handed out to the client. It deliberately does not contain
+/// anything identifying the correct answer.
+#[derive(Debug, Clone, Serialize)]
+pub struct Question {
+    pub token: String,
+    pub options: Vec<String>,
+}
+
+/// The state of a quiz session as seen by the client. `questions` only contains
+/// the questions that have not been answered yet.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionView {
+    pub session: String,
+    pub questions: Vec<Question>,
+    pub score: u32,
+    pub answered: u32,
+    pub finished: bool,
+    pub difficulty: Difficulty,
+    pub distractors: DistractorStrategy,
+}
+
+/// The result of answering a question, revealing the correct answer.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnswerResult {
+    pub correct: bool,
+    pub answer_info: TrackInfo,
+    pub score: u32,
+    pub answered: u32,
+}
+
+/// How a quiz is put together, as chosen by the player.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct QuizSettings {
+    /// Which tracks are asked about, and in what order.
+    pub difficulty: Difficulty,
+    /// How the wrong options are picked.
+    pub distractors: DistractorStrategy,
+}
+
+/// Generates a random opaque identifier.
+fn random_token<R: Rng>(rng: &mut R) -> String {
+    format!("{:032x}", rng.gen::<u128>())
+}
+
+/// Groups the tracks of an artist by title, as normalized by `titles`, so that
+/// tracks appearing on several albums, or in several versions, only make up a
+/// single question.
+pub fn group_by_title(tracks: Vec<TrackInfo>, titles: &TitleNormalizer) -> Vec<Vec<TrackInfo>> {
+    let mut keyed: Vec<_> = tracks
+        .into_iter()
+        .map(|track| (titles.key(&track.title), track))
+        .collect();
+    keyed.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    keyed
+        .chunk_by(|(a, _), (b, _)| a == b)
+        .map(|group| group.iter().map(|(_, track)| track.clone()).collect())
+        .collect()
+}
+
+/// Turns the tracks of an artist into a list of questions, each consisting of the
+/// track to be guessed and four shuffled title options, put together according
+/// to `settings`. Versions of the same song, as told by `titles`, are treated as one.
+pub fn generate_questions<R: Rng>(
+    tracks: Vec<TrackInfo>,
+    settings: QuizSettings,
+    titles: &TitleNormalizer,
+    rng: &mut R,
+) -> Vec<(TrackInfo, Vec<String>)> {
+    questions_from_groups(&group_by_title(tracks, titles), settings, rng)
+}
+
+/// Like [`generate_questions`], but for tracks already grouped with [`group_by_title`].
+pub fn questions_from_groups<R: Rng>(
+    groups: &[Vec<TrackInfo>],
+    settings: QuizSettings,
+    rng: &mut R,
+) -> Vec<(TrackInfo, Vec<String>)> {
+    // this filters out duplicates, keeping random entries each time to add variety
+    let tracks: Vec<TrackInfo> = groups
+        .iter()
+        .filter_map(|group| group.choose(rng).cloned())
+        .collect();
+
+    // finally, we pick and order the tracks to ask about
+    let asked = settings.difficulty.select(tracks.clone(), rng);
+
+    asked
+        .iter()
+        .map(|track| {
+            // wrong options can be any track, not just the ones asked about
+            let mut options = settings.distractors.pick(track, &tracks, 3, rng);
+
+            options.push(track.title.clone());
+            options.shuffle(rng); // reshuffle to ensure the random placement of the correct answer
+
+            (track.clone(), options)
+        })
+        .collect()
+}
+
+/// Keeps track of quiz sessions in the database, so that answers can be checked on
+/// the server and interrupted quizzes can be resumed.
+pub struct Sessions {
+    pool: PgPool,
+    titles: Arc<TitleNormalizer>,
+}
+
+impl Sessions {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            titles: Arc::default(),
+        }
+    }
+
+    /// Makes the sessions tell versions of the same song apart with `titles`.
+    pub fn with_titles(self, titles: Arc<TitleNormalizer>) -> Self {
+        Self { titles, ..self }
+    }
+
+    async fn question(&self, session: &str, token: &str) -> Result<QuizAnswerInfo, SessionError> {
+        let mut conn = self.pool.acquire().await?;
+        if QuizSessionInfo::get_from_id(&mut conn, session)
+            .await?
+            .is_none()
+        {
+            return Err(SessionError::NotFound);
+        }
+        QuizAnswerInfo::get(&mut conn, session, token)
+            .await?
+            .ok_or(SessionError::UnknownQuestion)
+    }
+
+    /// Starts a new session with questions about `tracks` of the artist with id
+    /// `artist`, put together according to `settings`.
+    pub async fn create(
+        &self,
+        artist: u32,
+        tracks: Vec<TrackInfo>,
+        settings: QuizSettings,
+    ) -> Result<SessionView, SessionError> {
+        self.create_from_groups(artist, &group_by_title(tracks, &self.titles), settings)
+            .await
+    }
+
+    /// Like [`Sessions::create`], but for tracks already grouped with [`group_by_title`].
+    pub async fn create_from_groups(
+        &self,
+        artist: u32,
+        groups: &[Vec<TrackInfo>],
+        settings: QuizSettings,
+    ) -> Result<SessionView, SessionError> {
+        let (id, questions) = {
+            let mut rng = thread_rng();
+            let questions: Vec<_> = questions_from_groups(groups, settings, &mut rng)
+                .into_iter()
+                .map(|q| (random_token(&mut rng), q))
+                .collect();
+            (random_token(&mut rng), questions)
+        };
+
+        let mut trans = self.pool.begin().await?;
+        QuizSessionInfo {
+            difficulty: settings.difficulty,
+            distractors: settings.distractors,
+            ..QuizSessionInfo::new(id.clone(), artist)
+        }
+        .insert(&mut trans)
+        .await?;
+
+        let mut client_questions = Vec::with_capacity(questions.len());
+        for (position, (token, (track, options))) in (0..).zip(questions) {
+            QuizAnswerInfo {
+                session: id.clone(),
+                position,
+                token: token.clone(),
+                track: track.id,
+                answer: track.title,
+                options: options.clone(),
+                chosen: None,
+                correct: None,
+                asked_at: None,
+                answered_at: None,
+                response_ms: None,
+            }
+            .insert(&mut trans)
+            .await?;
+            client_questions.push(Question { token, options });
+        }
+        trans.commit().await?;
+
+        Ok(SessionView {
+            session: id,
+            questions: client_questions,
+            score: 0,
+            answered: 0,
+            finished: false,
+            difficulty: settings.difficulty,
+            distractors: settings.distractors,
+        })
+    }
+
+    /// Retrieves the current state of `session`, for resuming it.
+    pub async fn resume(&self, session: &str) -> Result<SessionView, SessionError> {
+        let mut conn = self.pool.acquire().await?;
+        let info = QuizSessionInfo::get_from_id(&mut conn, session)
+            .await?
+            .ok_or(SessionError::NotFound)?;
+        let answers = QuizAnswerInfo::from_session(&mut conn, session).await?;
+
+        let score = answers.iter().filter(|a| a.correct == Some(true)).count() as u32;
+        let answered = answers.iter().filter(|a| a.chosen.is_some()).count() as u32;
+        let questions = answers
+            .into_iter()
+            .filter(|a| a.chosen.is_none())
+            .map(|a| Question {
+                token: a.token,
+                options: a.options,
+            })
+            .collect();
+
+        Ok(SessionView {
+            session: info.id,
+            questions,
+            score,
+            answered,
+            finished: info.finished,
+            difficulty: info.difficulty,
+            distractors: info.distractors,
+        })
+    }
+
+    /// Marks the question `token` in `session` as asked, returning the id of the
+    /// track that has to be guessed.
+    pub async fn ask(&self, session: &str, token: &str) -> Result<i64, SessionError> {
+        let mut question = self.question(session, token).await?;
+        question
+            .mark_asked(self.pool.acquire().await?.as_mut())
+            .await?;
+        Ok(question.track)
+    }
+
+    /// Checks `answer` against the question `token` in `session`. The question has
+    /// to have been asked, so that the time taken to answer it is known, and the
+    /// session must not be over yet, so that its score can't change once it's on the
+    /// leaderboard.
+    pub async fn answer(
+        &self,
+        session: &str,
+        token: &str,
+        answer: &str,
+    ) -> Result<AnswerResult, SessionError> {
+        let mut trans = self.pool.begin().await?;
+        let info = QuizSessionInfo::get_from_id(&mut trans, session)
+            .await?
+            .ok_or(SessionError::NotFound)?;
+        let mut question = QuizAnswerInfo::get(&mut trans, session, token)
+            .await?
+            .ok_or(SessionError::UnknownQuestion)?;
+        if question.chosen.is_some() {
+            return Err(SessionError::AlreadyAnswered);
+        }
+        if info.finished || info.submitted_at.is_some() {
+            return Err(SessionError::AlreadyFinished);
+        }
+        if question.asked_at.is_none() {
+            return Err(SessionError::NotAsked);
+        }
+        if !question.record_answer(&mut trans, answer).await? {
+            return Err(SessionError::AlreadyAnswered);
+        }
+
+        let score = QuizSessionInfo::score(&mut trans, session).await?;
+        QuizSessionInfo::update(&mut trans, session, score.answered == score.total).await?;
+        let answer_info = TrackInfo::get_from_id(&mut trans, question.track)
+            .await?
+            .ok_or(SessionError::TrackMissing)?;
+        trans.commit().await?;
+
+        Ok(AnswerResult {
+            correct: question.correct == Some(true),
+            answer_info,
+            score: score.score as u32,
+            answered: score.answered as u32,
+        })
+    }
+
+    /// Marks `session` as finished. Sessions without any answers can't be finished,
+    /// so that they never make it to the leaderboard.
+    pub async fn finish(&self, session: &str) -> Result<(), SessionError> {
+        let mut conn = self.pool.acquire().await?;
+        if QuizSessionInfo::get_from_id(&mut conn, session)
+            .await?
+            .is_none()
+        {
+            return Err(SessionError::NotFound);
+        }
+        if QuizSessionInfo::score(&mut conn, session).await?.answered == 0 {
+            return Err(SessionError::NothingAnswered);
+        }
+        QuizSessionInfo::update(&mut conn, session, true).await?;
+        Ok(())
+    }
+
+    /// Submits the finished `session` to the leaderboard under `name`. Each session
+    /// can only be submitted once.
+    pub async fn submit_name(&self, session: &str, name: &str) -> Result<(), SessionError> {
+        let name = name.trim();
+        if name.is_empty()
+            || name.chars().count() > MAX_NAME_LEN
+            || name.chars().any(char::is_control)
+        {
+            return Err(SessionError::InvalidName);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let info = QuizSessionInfo::get_from_id(&mut conn, session)
+            .await?
+            .ok_or(SessionError::NotFound)?;
+        if !info.finished {
+            return Err(SessionError::NotFinished);
+        }
+        if QuizSessionInfo::score(&mut conn, session).await?.answered == 0 {
+            return Err(SessionError::NothingAnswered);
+        }
+        if !QuizSessionInfo::submit_name(&mut conn, session, name).await? {
+            return Err(SessionError::AlreadySubmitted);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{AlbumInfo, ArtistInfo, LeaderboardEntry};
+    use chrono::Utc;
+
+    fn tracks() -> Vec<TrackInfo> {
+        ["One", "Two", "Three", "Four", "Five", "Five"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| TrackInfo::for_test(i as i64, t))
+            .collect()
+    }
+
+    #[test]
+    fn test_generate_questions() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let questions = generate_questions(
+            tracks(),
+            QuizSettings::default(),
+            &TitleNormalizer::default(),
+            &mut rng,
+        );
+
+        assert_eq!(questions.len(), 5);
+        for (track, options) in questions {
+            assert_eq!(options.len(), 4);
+            assert_eq!(options.iter().filter(|o| **o == track.title).count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_generate_questions_same_album() {
+        let tracks: Vec<_> = (0..8)
+            .map(|id| TrackInfo {
+                album_id: id % 2,
+                ..TrackInfo::for_test(id, &format!("Song {id}"))
+            })
+            .collect();
+        let settings = QuizSettings {
+            distractors: DistractorStrategy::SameAlbum,
+            ..QuizSettings::default()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        for (track, options) in generate_questions(
+            tracks.clone(),
+            settings,
+            &TitleNormalizer::default(),
+            &mut rng,
+        ) {
+            assert_eq!(options.len(), 4);
+            for option in options.iter().filter(|o| **o != track.title) {
+                let option = tracks.iter().find(|t| t.title == *option).unwrap();
+                assert_eq!(option.album_id, track.album_id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_group_versions() {
+        let tracks = [
+            "Song",
+            "Song (Remastered 2011)",
+            "Song - Live",
+            "Other Song",
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, t)| TrackInfo::for_test(i as i64, t))
+        .collect();
+        let groups = group_by_title(tracks, &TitleNormalizer::default());
+        let mut sizes: Vec<_> = groups.iter().map(Vec::len).collect();
+        sizes.sort();
+        assert_eq!(sizes, [1, 3]);
+    }
+
+    #[test]
+    fn test_other_versions_skipped() {
+        let mut tracks = tracks();
+        tracks.push(TrackInfo::for_test(6, "One (Live)"));
+        tracks.push(TrackInfo::for_test(7, "one - Remastered"));
+        for distractors in DistractorStrategy::ALL {
+            let settings = QuizSettings {
+                distractors,
+                ..QuizSettings::default()
+            };
+            for seed in 0..20 {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let titles = TitleNormalizer::default();
+                for (track, options) in
+                    generate_questions(tracks.clone(), settings, &titles, &mut rng)
+                {
+                    let key = titles.key(&track.title);
+                    assert_eq!(
+                        options.iter().filter(|o| titles.key(o) == key).count(),
+                        1,
+                        "{distractors:?} offered {options:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    async fn setup(pool: &PgPool) -> Sessions {
+        let mut conn = pool.acquire().await.unwrap();
+        ArtistInfo {
+            id: 1,
+            title: "Artist".to_owned(),
+            icon_url: "https://example.com/icon.png".to_owned(),
+            updated_at: Utc::now(),
+        }
+        .insert(&mut conn)
+        .await
+        .unwrap();
+        AlbumInfo {
+            id: 1,
+            artist: 1,
+            title: "Album".to_owned(),
+            cover_url: "https://example.com/cover.png".to_owned(),
+            release_date: None,
+        }
+        .insert(&mut conn)
+        .await
+        .unwrap();
+        for track in tracks() {
+            track.insert(&mut conn).await.unwrap();
+        }
+        Sessions::new(pool.clone())
+    }
+
+    async fn correct_answer(pool: &PgPool, session: &str, token: &str) -> String {
+        QuizAnswerInfo::get(pool.acquire().await.unwrap().as_mut(), session, token)
+            .await
+            .unwrap()
+            .unwrap()
+            .answer
+    }
+
+    async fn leaderboard(pool: &PgPool, settings: QuizSettings) -> Vec<LeaderboardEntry> {
+        LeaderboardEntry::for_artist(
+            pool.acquire().await.unwrap().as_mut(),
+            1,
+            settings.difficulty,
+            settings.distractors,
+            10,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_answer(pool: PgPool) {
+        let sessions = setup(&pool).await;
+        let view = sessions
+            .create(1, tracks(), QuizSettings::default())
+            .await
+            .unwrap();
+        let id = &view.session;
+        let q = &view.questions[0];
+        let answer = correct_answer(&pool, id, &q.token).await;
+
+        sessions.ask(id, &q.token).await.unwrap();
+        let res = sessions.answer(id, &q.token, &answer).await.unwrap();
+        assert!(res.correct);
+        assert_eq!(res.answer_info.title, answer);
+        assert_eq!(res.score, 1);
+        assert!(matches!(
+            sessions.answer(id, &q.token, &answer).await,
+            Err(SessionError::AlreadyAnswered)
+        ));
+
+        let q = &view.questions[1];
+        let answer = correct_answer(&pool, id, &q.token).await;
+        let wrong = q.options.iter().find(|o| **o != answer).unwrap();
+        sessions.ask(id, &q.token).await.unwrap();
+        let res = sessions.answer(id, &q.token, wrong).await.unwrap();
+        assert!(!res.correct);
+        assert_eq!(res.score, 1);
+        assert_eq!(res.answered, 2);
+    }
+
+    #[sqlx::test]
+    async fn test_resume(pool: PgPool) {
+        let sessions = setup(&pool).await;
+        let view = sessions
+            .create(1, tracks(), QuizSettings::default())
+            .await
+            .unwrap();
+        let id = &view.session;
+        let q = &view.questions[0];
+        sessions.ask(id, &q.token).await.unwrap();
+        let answer = correct_answer(&pool, id, &q.token).await;
+        sessions.answer(id, &q.token, &answer).await.unwrap();
+
+        let resumed = sessions.resume(id).await.unwrap();
+        assert_eq!(resumed.score, 1);
+        assert_eq!(resumed.answered, 1);
+        assert_eq!(resumed.questions.len(), view.questions.len() - 1);
+        assert_eq!(resumed.questions[0].token, view.questions[1].token);
+        assert!(!resumed.finished);
+
+        let stored = QuizAnswerInfo::get(pool.acquire().await.unwrap().as_mut(), id, &q.token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.response_ms.is_some());
+
+        sessions.finish(id).await.unwrap();
+        assert!(sessions.resume(id).await.unwrap().finished);
+    }
+
+    #[sqlx::test]
+    async fn test_submit_name(pool: PgPool) {
+        let sessions = setup(&pool).await;
+        let view = sessions
+            .create(1, tracks(), QuizSettings::default())
+            .await
+            .unwrap();
+        let id = &view.session;
+        let q = &view.questions[0];
+        let answer = correct_answer(&pool, id, &q.token).await;
+        sessions.ask(id, &q.token).await.unwrap();
+        sessions.answer(id, &q.token, &answer).await.unwrap();
+
+        assert!(matches!(
+            sessions.submit_name(id, "player").await,
+            Err(SessionError::NotFinished)
+        ));
+        sessions.finish(id).await.unwrap();
+        assert!(matches!(
+            sessions.submit_name(id, "   ").await,
+            Err(SessionError::InvalidName)
+        ));
+        sessions.submit_name(id, " player ").await.unwrap();
+        assert!(matches!(
+            sessions.submit_name(id, "someone else").await,
+            Err(SessionError::AlreadySubmitted)
+        ));
+
+        let entries = leaderboard(&pool, QuizSettings::default()).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].player_name, "player");
+        assert_eq!(entries[0].score, 1);
+        assert_eq!(entries[0].answered, 1);
+        assert_eq!(entries[0].percent_correct, 100.0);
+    }
+
+    #[sqlx::test]
+    async fn test_finish_unanswered(pool: PgPool) {
+        let sessions = setup(&pool).await;
+        let view = sessions
+            .create(1, tracks(), QuizSettings::default())
+            .await
+            .unwrap();
+        let id = &view.session;
+        assert!(matches!(
+            sessions.finish(id).await,
+            Err(SessionError::NothingAnswered)
+        ));
+        sessions.ask(id, &view.questions[0].token).await.unwrap();
+        assert!(matches!(
+            sessions.finish(id).await,
+            Err(SessionError::NothingAnswered)
+        ));
+        assert!(!sessions.resume(id).await.unwrap().finished);
+
+        // sessions finished before this was checked still can't be submitted
+        QuizSessionInfo::update(pool.acquire().await.unwrap().as_mut(), id, true)
+            .await
+            .unwrap();
+        assert!(matches!(
+            sessions.submit_name(id, "player").await,
+            Err(SessionError::NothingAnswered)
+        ));
+        assert!(leaderboard(&pool, QuizSettings::default()).await.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_answer_unasked(pool: PgPool) {
+        let sessions = setup(&pool).await;
+        let view = sessions
+            .create(1, tracks(), QuizSettings::default())
+            .await
+            .unwrap();
+        let id = &view.session;
+        let q = &view.questions[0];
+        let answer = correct_answer(&pool, id, &q.token).await;
+        assert!(matches!(
+            sessions.answer(id, &q.token, &answer).await,
+            Err(SessionError::NotAsked)
+        ));
+
+        sessions.ask(id, &q.token).await.unwrap();
+        sessions.answer(id, &q.token, &answer).await.unwrap();
+        let stored = QuizAnswerInfo::get(pool.acquire().await.unwrap().as_mut(), id, &q.token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.response_ms.is_some());
+    }
+
+    #[sqlx::test]
+    async fn test_answer_after_finish(pool: PgPool) {
+        let sessions = setup(&pool).await;
+        let view = sessions
+            .create(1, tracks(), QuizSettings::default())
+            .await
+            .unwrap();
+        let id = &view.session;
+        for q in &view.questions[..2] {
+            sessions.ask(id, &q.token).await.unwrap();
+        }
+        let q = &view.questions[0];
+        let answer = correct_answer(&pool, id, &q.token).await;
+        sessions.answer(id, &q.token, &answer).await.unwrap();
+
+        sessions.finish(id).await.unwrap();
+        let q = &view.questions[1];
+        let answer = correct_answer(&pool, id, &q.token).await;
+        assert!(matches!(
+            sessions.answer(id, &q.token, &answer).await,
+            Err(SessionError::AlreadyFinished)
+        ));
+
+        sessions.submit_name(id, "player").await.unwrap();
+        assert!(matches!(
+            sessions.answer(id, &q.token, &answer).await,
+            Err(SessionError::AlreadyFinished)
+        ));
+        let entries = leaderboard(&pool, QuizSettings::default()).await;
+        assert_eq!(entries[0].score, 1);
+        assert_eq!(entries[0].answered, 1);
+    }
+
+    #[sqlx::test]
+    async fn test_leaderboard_by_settings(pool: PgPool) {
+        let sessions = setup(&pool).await;
+        let settings = |difficulty, distractors| QuizSettings {
+            difficulty,
+            distractors,
+        };
+        let played = [
+            (
+                settings(Difficulty::Easy, DistractorStrategy::Random),
+                "easy",
+            ),
+            (
+                settings(Difficulty::Hard, DistractorStrategy::Random),
+                "hard",
+            ),
+            (
+                settings(Difficulty::Hard, DistractorStrategy::SameAlbum),
+                "hard same album",
+            ),
+        ];
+        for (settings, name) in played {
+            let view = sessions.create(1, tracks(), settings).await.unwrap();
+            assert_eq!(view.difficulty, settings.difficulty);
+            assert_eq!(view.distractors, settings.distractors);
+            let id = &view.session;
+            let q = &view.questions[0];
+            let answer = correct_answer(&pool, id, &q.token).await;
+            sessions.ask(id, &q.token).await.unwrap();
+            sessions.answer(id, &q.token, &answer).await.unwrap();
+            sessions.finish(id).await.unwrap();
+            let resumed = sessions.resume(id).await.unwrap();
+            assert_eq!(resumed.difficulty, settings.difficulty);
+            assert_eq!(resumed.distractors, settings.distractors);
+            sessions.submit_name(id, name).await.unwrap();
+        }
+
+        for (settings, name) in played {
+            let entries = leaderboard(&pool, settings).await;
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].player_name, name);
+        }
+        assert!(leaderboard(&pool, QuizSettings::default()).await.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_unknown_session(pool: PgPool) {
+        let sessions = setup(&pool).await;
+        let view = sessions
+            .create(1, tracks(), QuizSettings::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            sessions.answer("nope", "nope", "One").await,
+            Err(SessionError::NotFound)
+        ));
+        assert!(matches!(
+            sessions.answer(&view.session, "nope", "One").await,
+            Err(SessionError::UnknownQuestion)
+        ));
+    }
+}
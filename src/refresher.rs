@@ -0,0 +1,175 @@
+//! Keeping popular artists fresh in the cache ahead of time, so that nobody has to
+//! wait for them to load.
+
+use std::{num::ParseIntError, sync::Arc};
+
+use chrono::TimeDelta;
+use serde::Deserialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{deser_timedelta, state::QuizState};
+
+/// Configures the background refresher.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RefresherConfig {
+    /// How often the refresher runs.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub interval: TimeDelta,
+    /// The most artists refreshed per run. 0 disables the refresher.
+    pub artists: usize,
+    /// How far back plays and accesses are counted when deciding which artists are
+    /// popular. Artists neither played nor accessed in this long aren't refreshed.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub popularity_window: TimeDelta,
+}
+
+impl Default for RefresherConfig {
+    fn default() -> Self {
+        Self {
+            interval: TimeDelta::try_minutes(10).unwrap(),
+            artists: 20,
+            popularity_window: TimeDelta::try_days(7).unwrap(),
+        }
+    }
+}
+
+/// Runs the refresher every `config.interval`, forever.
+pub async fn run(state: Arc<QuizState>, config: RefresherConfig) {
+    let Ok(period) = config.interval.to_std() else {
+        log::error!("Invalid refresher interval {}", config.interval);
+        return;
+    };
+    if config.artists == 0 || period.is_zero() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        refresh_due(&state, &config).await;
+        log::info!("Track cache: {}", state.track_cache_stats());
+    }
+}
+
+/// Refreshes the most played of the recently used artists that would go stale
+/// before the next run. Returns the number of artists refreshed.
+pub async fn refresh_due(state: &QuizState, config: &RefresherConfig) -> usize {
+    let due = match state
+        .artists_due_for_refresh(config.interval, config.popularity_window, config.artists)
+        .await
+    {
+        Ok(due) => due,
+        Err(e) => {
+            log::error!("Could not get artists due for refresh: {e}");
+            return 0;
+        }
+    };
+    let total = due.len();
+    total - warm(state, due).await
+}
+
+/// Refreshes each of `artists` in turn, caching them if they aren't yet. Returns
+/// the number of artists that failed.
+pub async fn warm(state: &QuizState, artists: impl IntoIterator<Item = u32>) -> usize {
+    let mut failed = 0;
+    for artist in artists {
+        if let Err(e) = state.refresh_artist(artist).await {
+            log::warn!("Could not refresh artist {artist}: {e}");
+            failed += 1;
+        }
+    }
+    failed
+}
+
+/// Parses a list of artist ids separated by whitespace, where `#` starts a comment
+/// running to the end of the line.
+pub fn parse_artist_ids(s: &str) -> Result<Vec<u32>, ParseIntError> {
+    s.lines()
+        .flat_map(|line| {
+            line.split('#')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+        })
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{PgPool, Postgres};
+
+    use super::*;
+    use crate::db::QuizSessionInfo;
+    use crate::source::tests::fake_source;
+
+    #[test]
+    fn test_parse_artist_ids() {
+        let ids = parse_artist_ids("1 2\n# popular ones\n3  # Mili\n\n4\t5").unwrap();
+        assert_eq!(ids, [1, 2, 3, 4, 5]);
+        assert!(parse_artist_ids("1 two").is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_warm_and_refresh(pool: sqlx::Pool<Postgres>) {
+        let mut source = fake_source();
+        let mut other = source.artists[0].clone();
+        other.id = 2;
+        other.albums.clear();
+        source.artists.push(other);
+
+        let state = QuizState::with_pool(
+            pool.clone(),
+            TimeDelta::try_hours(1).unwrap(),
+            None,
+            Arc::new(source),
+        );
+        assert_eq!(warm(&state, [1, 2, 3]).await, 1);
+
+        let config = RefresherConfig::default();
+        // nothing is close to going stale
+        assert_eq!(refresh_due(&state, &config).await, 0);
+
+        // artist 2 is played, so goes before artist 1, which was only accessed
+        age_artists(&pool).await;
+        for (id, artist) in [("a", 2), ("b", 2)] {
+            QuizSessionInfo::new(id.to_owned(), artist)
+                .insert(&mut pool.acquire().await.unwrap())
+                .await
+                .unwrap();
+        }
+        let due = state
+            .artists_due_for_refresh(config.interval, config.popularity_window, 10)
+            .await
+            .unwrap();
+        assert_eq!(due, [2, 1]);
+        let due = state
+            .artists_due_for_refresh(config.interval, config.popularity_window, 1)
+            .await
+            .unwrap();
+        assert_eq!(due, [2]);
+
+        // artists nobody has used lately aren't kept fresh
+        sqlx::query("update artists set last_accessed_at = now() - interval '8 days' where id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let due = state
+            .artists_due_for_refresh(config.interval, config.popularity_window, 10)
+            .await
+            .unwrap();
+        assert_eq!(due, [2]);
+
+        assert_eq!(refresh_due(&state, &config).await, 1);
+        assert_eq!(refresh_due(&state, &config).await, 0);
+    }
+
+    async fn age_artists(pool: &PgPool) {
+        sqlx::query("update artists set updated_at = now() - interval '55 minutes'")
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+}
@@ -1,11 +1,17 @@
 use std::fmt::Debug;
 use std::time::Duration;
 
-use crate::db::TrackInfo;
-use crate::deezer::Artist;
+use crate::difficulty::Difficulty;
+use crate::distractors::DistractorStrategy;
+use crate::gc;
+use crate::library::clip::{self, ClipError, CLIP_LENGTH};
+use crate::loading::LoadingError;
+use crate::quiz::{QuizSettings, SessionError};
+use crate::refresher;
+use crate::source::{self, SourceInitError};
 use crate::Config;
 use crate::{
-    db::ArtistInfo,
+    db::{ArtistInfo, LeaderboardEntry},
     deezer,
     state::{QuizState, RetrievalError},
 };
@@ -14,29 +20,48 @@ use actix_web::dev::Service;
 use actix_web::http::header::{CacheDirective, HeaderValue};
 use actix_web::http::{header, StatusCode};
 use actix_web::middleware::{DefaultHeaders, Logger, NormalizePath, TrailingSlash};
-use actix_web::web::{PathConfig, QueryConfig};
-use actix_web::{body::BoxBody, get, web, HttpResponse, ResponseError};
+use actix_web::web::{JsonConfig, PathConfig, QueryConfig};
+use actix_web::{body::BoxBody, get, post, web, HttpResponse, ResponseError};
 use actix_web::{App, HttpServer, Responder};
 use actix_web_lab::middleware::CatchPanic;
+use actix_web_lab::sse;
 use askama::Template;
 use askama_actix::TemplateToResponse;
 use chrono::Utc;
-use rand::prelude::*;
-use serde::{Deserialize, Serialize};
+use futures::stream::{self, StreamExt};
+use serde::Deserialize;
 use std::error::Error;
 use thiserror::Error;
 use tokio::select;
+use tokio_stream::wrappers::WatchStream;
 
 #[derive(Debug, Error)]
 enum QuizError {
-    #[error("deezer error")]
-    Deezer(#[from] deezer::Error), // 19
+    #[error("music source error")]
+    Source(#[from] source::Error),
+    #[error("not found")]
+    NotFound,
     #[error("database error")]
     DbError(#[from] sqlx::Error),
     #[error("unkown internal error")]
     UnknownError,
     #[error("timeout")]
-    Timeout,
+    Timeout(u32),
+    #[error("quiz session error")]
+    Session(#[from] SessionError),
+    #[error("loading error")]
+    Loading(#[from] LoadingError),
+    #[error("preview clip error")]
+    Clip(#[from] ClipError),
+}
+
+/// An error on one of the JSON endpoints, sent as `{"error": message}` rather
+/// than as an error page.
+#[derive(Debug, Error)]
+#[error("{message}")]
+struct JsonError {
+    status: StatusCode,
+    message: String,
 }
 
 #[derive(Template)]
@@ -45,7 +70,9 @@ struct InternalErrorView;
 
 #[derive(Template)]
 #[template(path = "errors/loading.html")]
-struct LoadingErrorView;
+struct LoadingErrorView {
+    artist: u32,
+}
 
 #[derive(Debug, Error, Template)]
 #[template(path = "errors/invalidurl.html")]
@@ -65,13 +92,59 @@ struct NotFoundView;
 impl From<RetrievalError> for QuizError {
     fn from(value: RetrievalError) -> Self {
         match value {
+            RetrievalError::NotFound => Self::NotFound,
             RetrievalError::CacheUpdateInternalError => Self::UnknownError,
-            RetrievalError::ApiError(err) => Self::Deezer(deezer::Error::ApiError(err)),
+            RetrievalError::ApiError(err) => {
+                Self::Source(source::Error::Deezer(deezer::Error::ApiError(err)))
+            }
             RetrievalError::DbError(err) => Self::DbError(err),
+            RetrievalError::SessionError(err) => Self::Session(err),
+            RetrievalError::LoadingError(err) => Self::Loading(err),
+        }
+    }
+}
+
+impl From<QuizError> for JsonError {
+    fn from(err: QuizError) -> Self {
+        let message = match &err {
+            QuizError::Session(err) => err.to_string(),
+            err => err.to_string(),
+        };
+        Self {
+            status: err.status_code(),
+            message,
         }
     }
 }
 
+impl From<SessionError> for JsonError {
+    fn from(err: SessionError) -> Self {
+        QuizError::from(err).into()
+    }
+}
+
+impl From<RetrievalError> for JsonError {
+    fn from(err: RetrievalError) -> Self {
+        QuizError::from(err).into()
+    }
+}
+
+impl From<sqlx::Error> for JsonError {
+    fn from(err: sqlx::Error) -> Self {
+        QuizError::from(err).into()
+    }
+}
+
+impl ResponseError for JsonError {
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        HttpResponse::build(self.status).json(serde_json::json!({ "error": self.message }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+}
+
 impl<E: Debug + Error> ResponseError for InvalidReqView<E> {
     fn error_response(&self) -> HttpResponse<BoxBody> {
         let mut resp = self.to_response();
@@ -87,10 +160,12 @@ impl<E: Debug + Error> ResponseError for InvalidReqView<E> {
 impl ResponseError for QuizError {
     fn error_response(&self) -> HttpResponse<BoxBody> {
         let mut resp = match self {
-            Self::Deezer(deezer::Error::ApiError(deezer::ApiErrCode::DataNotFound)) => {
-                NotFoundView.to_response()
-            }
-            Self::Timeout => LoadingErrorView.to_response(),
+            Self::NotFound
+            | Self::Source(source::Error::NotFound)
+            | Self::Session(
+                SessionError::NotFound | SessionError::UnknownQuestion | SessionError::TrackMissing,
+            ) => NotFoundView.to_response(),
+            Self::Timeout(artist) => LoadingErrorView { artist: *artist }.to_response(),
             _ => InternalErrorView.to_response(),
         };
         *resp.status_mut() = self.status_code();
@@ -99,10 +174,24 @@ impl ResponseError for QuizError {
 
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::Deezer(deezer::Error::ApiError(deezer::ApiErrCode::DataNotFound)) => {
-                StatusCode::NOT_FOUND
+            Self::NotFound | Self::Source(source::Error::NotFound) => StatusCode::NOT_FOUND,
+            Self::Timeout(_) | Self::Loading(LoadingError::TimedOut | LoadingError::Aborted) => {
+                StatusCode::SERVICE_UNAVAILABLE
             }
-            Self::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Session(
+                SessionError::AlreadyAnswered
+                | SessionError::AlreadySubmitted
+                | SessionError::AlreadyFinished,
+            ) => StatusCode::CONFLICT,
+            Self::Session(
+                SessionError::NotFinished
+                | SessionError::InvalidName
+                | SessionError::NotAsked
+                | SessionError::NothingAnswered,
+            ) => StatusCode::BAD_REQUEST,
+            Self::Session(
+                SessionError::NotFound | SessionError::UnknownQuestion | SessionError::TrackMissing,
+            ) => StatusCode::NOT_FOUND,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -114,19 +203,24 @@ struct ArtistPageView {
     artist: ArtistInfo,
 }
 
+/// Retrieves an artist, giving up with [`QuizError::Timeout`] if it takes too long to load.
+async fn get_artist_timeout(state: &QuizState, id: u32) -> Result<ArtistInfo, QuizError> {
+    select! {
+        artist = state.get_artist(id) => {
+            Ok(artist?)
+        }
+        _ = tokio::time::sleep(Duration::from_secs(5)) => {
+            Err(QuizError::Timeout(id))
+        }
+    }
+}
+
 #[get("/artist/{id}")]
 async fn artist_page(
     state: web::Data<QuizState>,
     id: web::Path<u32>,
 ) -> Result<impl Responder, QuizError> {
-    let artist = select! {
-        artist = state.get_artist(*id) => {
-            artist?
-        }
-        _ = tokio::time::sleep(Duration::from_secs(5)) => {
-            return Err(QuizError::Timeout)
-        }
-    };
+    let artist = get_artist_timeout(&state, *id).await?;
 
     let updated_at = artist.updated_at;
     let resp = ArtistPageView { artist }
@@ -135,10 +229,17 @@ async fn artist_page(
     Ok(resp)
 }
 
-#[derive(Serialize)]
-struct Question {
-    answer_info: TrackInfo,
-    options: Vec<String>,
+/// Streams the progress of loading an artist as server-sent `progress` events,
+/// followed by a `done` event once it's no longer loading.
+#[get("/artist/{id}/progress")]
+async fn artist_progress(state: web::Data<QuizState>, id: web::Path<u32>) -> impl Responder {
+    let events = stream::iter(state.load_progress(*id))
+        .flat_map(WatchStream::new)
+        .map(|p| Ok(sse::Data::new_json(p)?.event("progress").into()))
+        .chain(stream::once(async {
+            Ok::<_, serde_json::Error>(sse::Data::new("").event("done").into())
+        }));
+    sse::Sse::from_stream(events).with_keep_alive(Duration::from_secs(15))
 }
 
 #[get(
@@ -148,42 +249,135 @@ struct Question {
 async fn artist_questions(
     state: web::Data<QuizState>,
     id: web::Path<u32>,
+    settings: web::Query<QuizSettings>,
+) -> Result<impl Responder, JsonError> {
+    Ok(web::Json(state.start_quiz(*id, *settings).await?))
+}
+
+#[get(
+    "/quiz/{session}",
+    wrap = "DefaultHeaders::default().add(header::CacheControl(vec![CacheDirective::NoCache]))"
+)]
+async fn quiz_resume(
+    state: web::Data<QuizState>,
+    session: web::Path<String>,
+) -> Result<impl Responder, JsonError> {
+    Ok(web::Json(state.resume_quiz(&session).await?))
+}
+
+#[derive(Deserialize)]
+struct AnswerParams {
+    token: String,
+    answer: String,
+}
+
+#[post("/quiz/{session}/answer")]
+async fn quiz_answer(
+    state: web::Data<QuizState>,
+    session: web::Path<String>,
+    params: web::Json<AnswerParams>,
+) -> Result<impl Responder, JsonError> {
+    let result = state
+        .answer(&session, &params.token, &params.answer)
+        .await?;
+    Ok(web::Json(result))
+}
+
+#[post("/quiz/{session}/finish")]
+async fn quiz_finish(
+    state: web::Data<QuizState>,
+    session: web::Path<String>,
+) -> Result<impl Responder, JsonError> {
+    state.finish_quiz(&session).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get(
+    "/quiz/{session}/{token}/preview",
+    wrap = "DefaultHeaders::default().add(header::CacheControl(vec![CacheDirective::NoStore]))"
+)]
+async fn question_preview(
+    state: web::Data<QuizState>,
+    path: web::Path<(String, String)>,
 ) -> Result<impl Responder, QuizError> {
-    let mut tracks = state.get_artist_tracks(*id).await?;
-    let mut rng = thread_rng();
+    let (session, token) = path.into_inner();
+    let track = state.ask(&session, &token).await?;
+    let preview = state.track_preview(track).await?;
+    Ok(HttpResponse::TemporaryRedirect()
+        .insert_header((header::LOCATION, preview))
+        .finish())
+}
 
-    // this filters out duplicates, keeping random entries each time to add variety
-    tracks.sort_unstable_by(|a, b| a.title.cmp(&b.title));
+#[get("/library/tracks/{id}")]
+async fn library_track(
+    state: web::Data<QuizState>,
+    id: web::Path<i64>,
+) -> Result<impl Responder, QuizError> {
+    let path = state.local_track_path(*id).ok_or(QuizError::NotFound)?;
+    let clip = web::block(move || clip::preview_clip(&path, CLIP_LENGTH))
+        .await
+        .map_err(|_| QuizError::UnknownError)??;
+    Ok(HttpResponse::Ok()
+        .content_type(clip.content_type)
+        .body(clip.data))
+}
 
-    for chunk in tracks.chunk_by_mut(|a, b| a.title == b.title) {
-        chunk.shuffle(&mut rng);
-    }
+/// How many entries are shown on a leaderboard.
+const LEADERBOARD_SIZE: i64 = 50;
 
-    tracks.dedup_by(|a, b| a.title == b.title);
+#[derive(Template)]
+#[template(path = "leaderboard.html", escape = "html")]
+struct LeaderboardView {
+    artist: ArtistInfo,
+    settings: QuizSettings,
+    entries: Vec<LeaderboardEntry>,
+}
 
-    // finally, we shuffle all the tracks
-    tracks.shuffle(&mut rng);
+#[get(
+    "/artist/{id}/leaderboard",
+    wrap = "DefaultHeaders::default().add(header::CacheControl(vec![CacheDirective::NoCache]))"
+)]
+async fn artist_leaderboard(
+    state: web::Data<QuizState>,
+    id: web::Path<u32>,
+    settings: web::Query<QuizSettings>,
+) -> Result<impl Responder, QuizError> {
+    let artist = get_artist_timeout(&state, *id).await?;
+    let entries = state.leaderboard(*id, *settings, LEADERBOARD_SIZE).await?;
+    Ok(LeaderboardView {
+        artist,
+        settings: *settings,
+        entries,
+    })
+}
 
-    let questions: Vec<_> = tracks
-        .iter()
-        .map(|track| {
-            let mut options: Vec<_> = tracks
-                .choose_multiple(&mut rng, 4) // we choose 4 so we can discard one if it is a duplicate
-                .filter(|qtr| qtr.id != track.id) // filter out the current track in case it happened to be chosen
-                .map(|qtr| qtr.title.clone())
-                .take(3)
-                .collect();
+#[get(
+    "/artist/{id}/leaderboard.json",
+    wrap = "DefaultHeaders::default().add(header::CacheControl(vec![CacheDirective::NoCache]))"
+)]
+async fn artist_leaderboard_json(
+    state: web::Data<QuizState>,
+    id: web::Path<u32>,
+    settings: web::Query<QuizSettings>,
+) -> Result<impl Responder, JsonError> {
+    Ok(web::Json(
+        state.leaderboard(*id, *settings, LEADERBOARD_SIZE).await?,
+    ))
+}
 
-            options.push(track.title.clone());
-            options.shuffle(&mut rng); // reshuffle to ensure the random placement of the correct answer
+#[derive(Deserialize)]
+struct NameParams {
+    name: String,
+}
 
-            Question {
-                answer_info: track.clone(),
-                options,
-            }
-        })
-        .collect();
-    Ok(web::Json(questions))
+#[post("/quiz/{session}/name")]
+async fn quiz_submit_name(
+    state: web::Data<QuizState>,
+    session: web::Path<String>,
+    params: web::Json<NameParams>,
+) -> Result<impl Responder, JsonError> {
+    state.submit_name(&session, &params.name).await?;
+    Ok(HttpResponse::NoContent().finish())
 }
 
 #[derive(Deserialize)]
@@ -194,7 +388,7 @@ struct SearchParams {
 #[derive(Template)]
 #[template(path = "search.html", escape = "html")]
 struct SearchView {
-    results: Vec<Artist>,
+    results: Vec<ArtistInfo>,
 }
 
 #[get("/")]
@@ -203,7 +397,7 @@ async fn search(
     query: web::Query<SearchParams>,
 ) -> Result<SearchView, QuizError> {
     let results = match &query.q {
-        Some(q) if q != "" => state.search_artists(q, 0, 10).await?.data,
+        Some(q) if !q.is_empty() => state.search_artists(q, 0, 10).await?,
         _ => Vec::new(),
     };
     Ok(SearchView { results })
@@ -217,26 +411,65 @@ pub enum QuizInitError {
     IoError(#[from] std::io::Error),
     #[error("config parsing error")]
     ConfigError(#[from] toml::de::Error),
+    #[error("music source initialization error")]
+    SourceError(#[from] SourceInitError),
+}
+
+/// Creates the quiz state described by `c`.
+pub fn build_state(c: &Config) -> Result<QuizState, QuizInitError> {
+    let state = QuizState::new(
+        &c.database_url,
+        c.cache_duration,
+        c.cache_max_age,
+        c.source.build()?,
+    )?
+    .with_negative_cache(c.negative_cache.clone())
+    .with_track_cache(c.track_cache.clone())
+    .with_titles(&c.titles);
+    Ok(match c.load_timeout {
+        Some(timeout) => state.with_load_timeout(timeout),
+        None => state,
+    })
 }
 
 pub async fn start_server(c: Config) -> Result<(), QuizInitError> {
+    let data = web::Data::new(build_state(&c)?);
     let Config {
-        database_url,
         cache_duration,
         bind_address,
+        refresher,
+        gc,
+        ..
     } = c;
 
-    let data = web::Data::new(QuizState::new(&database_url, cache_duration)?);
+    tokio::spawn(refresher::run(data.clone().into_inner(), refresher));
+    tokio::spawn(gc::run(data.clone().into_inner(), gc));
 
     HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
             .service(fs::Files::new("/static", "static"))
             .service(artist_page)
+            .service(artist_progress)
             .service(artist_questions)
+            .service(quiz_resume)
+            .service(quiz_answer)
+            .service(quiz_finish)
+            .service(quiz_submit_name)
+            .service(artist_leaderboard_json)
+            .service(artist_leaderboard)
+            .service(question_preview)
+            .service(library_track)
             .service(search)
             .app_data(PathConfig::default().error_handler(|err, _| InvalidReqView { err }.into()))
             .app_data(QueryConfig::default().error_handler(|err, _| InvalidReqView { err }.into()))
+            .app_data(JsonConfig::default().error_handler(|err, _| {
+                JsonError {
+                    status: StatusCode::BAD_REQUEST,
+                    message: err.to_string(),
+                }
+                .into()
+            }))
             .default_service(web::to(|| async {
                 (UrlNotFoundView, StatusCode::NOT_FOUND)
             }))
@@ -266,3 +499,97 @@ pub async fn start_server(c: Config) -> Result<(), QuizInitError> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{test, App};
+    use chrono::TimeDelta;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::library::{test_files::flac_file, LibrarySource};
+    use crate::source::{FakeSource, MusicSource};
+
+    #[actix_web::test]
+    async fn test_library_track() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("01.flac"),
+            flac_file(&["ARTIST=Local Band", "TITLE=Secret Title"], 4),
+        )
+        .unwrap();
+        let library = LibrarySource::scan(dir.path()).unwrap();
+        let artist = library.search_artists("", 0, 1).await.unwrap().remove(0);
+        let album = library
+            .artist_albums(artist.id as u32)
+            .await
+            .unwrap()
+            .remove(0);
+        let track = library.album_tracks(&album).await.unwrap().remove(0);
+
+        // serving the clip doesn't touch the database
+        let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let state = QuizState::with_pool(pool, TimeDelta::zero(), None, Arc::new(library));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(library_track),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&track.preview_url)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "audio/flac"
+        );
+        let body = test::read_body(resp).await;
+        assert!(body.starts_with(b"fLaC"));
+        assert!(!body.windows(6).any(|w| w == b"Secret"));
+
+        let req = test::TestRequest::get()
+            .uri("/library/tracks/1")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn test_json_errors(pool: PgPool) {
+        let state = QuizState::with_pool(
+            pool,
+            TimeDelta::zero(),
+            None,
+            Arc::new(FakeSource::default()),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(quiz_finish)
+                .service(quiz_submit_name),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/quiz/unknown/finish")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "no such quiz session");
+
+        let req = test::TestRequest::post()
+            .uri("/quiz/unknown/name")
+            .set_json(serde_json::json!({ "name": " " }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid player name");
+    }
+}
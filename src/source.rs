@@ -0,0 +1,331 @@
+use std::{fs, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    db::{AlbumInfo, ArtistInfo, TrackInfo},
+    deezer::{self, Deezer, DeezerConfig},
+    library::{LibraryError, LibrarySource},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("not found")]
+    NotFound,
+    #[error("deezer error")]
+    Deezer(#[source] deezer::Error),
+}
+
+impl From<deezer::Error> for Error {
+    fn from(value: deezer::Error) -> Self {
+        match value {
+            deezer::Error::ApiError(deezer::ApiErrCode::DataNotFound) => Error::NotFound,
+            e => Error::Deezer(e),
+        }
+    }
+}
+
+/// How urgently a request needs to be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Someone is waiting on the response, e.g. a search.
+    Interactive,
+    /// The response is part of a longer job, e.g. crawling a catalogue. These
+    /// are only sent when no interactive requests are waiting.
+    Background,
+}
+
+/// A catalogue of music that quizzes can be made from.
+#[async_trait]
+pub trait MusicSource: Send + Sync {
+    /// Searches for artists with names matching `q`.
+    async fn search_artists(
+        &self,
+        q: &str,
+        index: u32,
+        limit: u32,
+    ) -> Result<Vec<ArtistInfo>, Error>;
+
+    /// Gets the artist with id `id`, as urgently as `priority` says.
+    async fn artist(&self, id: u32, priority: Priority) -> Result<ArtistInfo, Error>;
+
+    /// Lists all albums by the artist with id `id`.
+    async fn artist_albums(&self, id: u32) -> Result<Vec<AlbumInfo>, Error>;
+
+    /// Lists all tracks in `album`.
+    async fn album_tracks(&self, album: &AlbumInfo) -> Result<Vec<TrackInfo>, Error>;
+
+    /// Gets a playable preview URL for the track with id `track`. This may be
+    /// relative, if the preview is served by the quiz itself.
+    async fn preview_url(&self, track: i64) -> Result<String, Error>;
+
+    /// Gets the path of the audio file for the track with id `track`, for sources
+    /// whose previews are served by the quiz itself.
+    fn local_path(&self, _track: i64) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Selects the [`MusicSource`] used by the quiz.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceConfig {
+    /// The Deezer API.
+    Deezer(DeezerConfig),
+    /// A [`FakeSource`] loaded from a JSON catalogue, for offline development.
+    Fake { catalogue: PathBuf },
+    /// A [`LibrarySource`] scanning a directory of audio files.
+    Library { path: PathBuf },
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        SourceConfig::Deezer(DeezerConfig::default())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SourceInitError {
+    #[error("IO error")]
+    IoError(#[from] std::io::Error),
+    #[error("catalogue parsing error")]
+    CatalogueError(#[from] serde_json::Error),
+    #[error("library scanning error")]
+    LibraryError(#[from] LibraryError),
+}
+
+impl SourceConfig {
+    /// Creates the music source described by `self`.
+    pub fn build(&self) -> Result<Arc<dyn MusicSource>, SourceInitError> {
+        Ok(match self {
+            SourceConfig::Deezer(config) => Arc::new(Deezer::from_config(config)),
+            SourceConfig::Fake { catalogue } => Arc::new(serde_json::from_str::<FakeSource>(
+                &fs::read_to_string(catalogue)?,
+            )?),
+            SourceConfig::Library { path } => Arc::new(LibrarySource::scan(path)?),
+        })
+    }
+}
+
+/// A track in a [`FakeSource`] catalogue.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FakeTrack {
+    pub id: i64,
+    pub title: String,
+    pub preview_url: String,
+    #[serde(default)]
+    pub duration: Option<i32>,
+    #[serde(default)]
+    pub rank: Option<i32>,
+    #[serde(default)]
+    pub explicit: Option<bool>,
+    #[serde(default)]
+    pub track_position: Option<i32>,
+    #[serde(default)]
+    pub disc_number: Option<i32>,
+    #[serde(default)]
+    pub isrc: Option<String>,
+}
+
+/// An album in a [`FakeSource`] catalogue.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FakeAlbum {
+    pub id: i64,
+    pub title: String,
+    pub cover_url: String,
+    #[serde(default)]
+    pub release_date: Option<NaiveDate>,
+    pub tracks: Vec<FakeTrack>,
+}
+
+/// An artist in a [`FakeSource`] catalogue.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FakeArtist {
+    pub id: i64,
+    pub title: String,
+    pub icon_url: String,
+    pub albums: Vec<FakeAlbum>,
+}
+
+/// An in-memory music source, intended for tests and offline development.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FakeSource {
+    pub artists: Vec<FakeArtist>,
+}
+
+impl FakeSource {
+    pub fn new(artists: Vec<FakeArtist>) -> Self {
+        Self { artists }
+    }
+
+    fn find_artist(&self, id: u32) -> Result<&FakeArtist, Error> {
+        self.artists
+            .iter()
+            .find(|a| a.id == i64::from(id))
+            .ok_or(Error::NotFound)
+    }
+
+    fn artist_info(artist: &FakeArtist) -> ArtistInfo {
+        ArtistInfo {
+            id: artist.id,
+            title: artist.title.clone(),
+            icon_url: artist.icon_url.clone(),
+            updated_at: Utc::now(),
+        }
+    }
+}
+
+#[async_trait]
+impl MusicSource for FakeSource {
+    async fn search_artists(
+        &self,
+        q: &str,
+        index: u32,
+        limit: u32,
+    ) -> Result<Vec<ArtistInfo>, Error> {
+        let q = q.to_lowercase();
+        Ok(self
+            .artists
+            .iter()
+            .filter(|a| a.title.to_lowercase().contains(&q))
+            .skip(index as usize)
+            .take(limit as usize)
+            .map(FakeSource::artist_info)
+            .collect())
+    }
+
+    async fn artist(&self, id: u32, _priority: Priority) -> Result<ArtistInfo, Error> {
+        self.find_artist(id).map(FakeSource::artist_info)
+    }
+
+    async fn artist_albums(&self, id: u32) -> Result<Vec<AlbumInfo>, Error> {
+        let artist = self.find_artist(id)?;
+        Ok(artist
+            .albums
+            .iter()
+            .map(|album| AlbumInfo {
+                id: album.id,
+                artist: artist.id,
+                title: album.title.clone(),
+                cover_url: album.cover_url.clone(),
+                release_date: album.release_date,
+            })
+            .collect())
+    }
+
+    async fn album_tracks(&self, album: &AlbumInfo) -> Result<Vec<TrackInfo>, Error> {
+        let tracks = self
+            .artists
+            .iter()
+            .flat_map(|a| &a.albums)
+            .find(|a| a.id == album.id)
+            .ok_or(Error::NotFound)?
+            .tracks
+            .iter()
+            .map(|track| TrackInfo {
+                id: track.id,
+                album_title: album.title.clone(),
+                album_cover_url: album.cover_url.clone(),
+                album_id: album.id,
+                album_release_date: album.release_date,
+                title: track.title.clone(),
+                preview_url: track.preview_url.clone(),
+                duration: track.duration,
+                rank: track.rank,
+                explicit: track.explicit,
+                track_position: track.track_position,
+                disc_number: track.disc_number,
+                isrc: track.isrc.clone(),
+            })
+            .collect();
+        Ok(tracks)
+    }
+
+    async fn preview_url(&self, track: i64) -> Result<String, Error> {
+        let track = self
+            .artists
+            .iter()
+            .flat_map(|a| &a.albums)
+            .flat_map(|a| &a.tracks)
+            .find(|t| t.id == track)
+            .ok_or(Error::NotFound)?;
+        Ok(track.preview_url.clone())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A small catalogue with a single artist, for use in tests.
+    pub(crate) fn fake_source() -> FakeSource {
+        let track = |id: i64, title: &str, rank: i32| FakeTrack {
+            id,
+            title: title.to_owned(),
+            preview_url: format!("https://example.com/preview/{id}.mp3"),
+            duration: Some(180),
+            rank: Some(rank),
+            explicit: Some(false),
+            track_position: Some(id as i32 % 10 + 1),
+            disc_number: Some(1),
+            isrc: None,
+        };
+        FakeSource::new(vec![FakeArtist {
+            id: 1,
+            title: "Fake Artist".to_owned(),
+            icon_url: "https://example.com/icon.png".to_owned(),
+            albums: vec![
+                FakeAlbum {
+                    id: 10,
+                    title: "First Album".to_owned(),
+                    cover_url: "https://example.com/10.png".to_owned(),
+                    release_date: NaiveDate::from_ymd_opt(2015, 3, 1),
+                    tracks: vec![
+                        track(100, "Opening", 500_000),
+                        track(101, "Second Song", 20_000),
+                    ],
+                },
+                FakeAlbum {
+                    id: 11,
+                    title: "Second Album".to_owned(),
+                    cover_url: "https://example.com/11.png".to_owned(),
+                    release_date: NaiveDate::from_ymd_opt(2019, 9, 20),
+                    tracks: vec![
+                        track(110, "Another One", 300_000),
+                        track(111, "Closing", 80_000),
+                    ],
+                },
+            ],
+        }])
+    }
+
+    #[tokio::test]
+    async fn test_fake_source() -> Result<(), Error> {
+        let source = fake_source();
+        assert_eq!(source.search_artists("fake", 0, 10).await?.len(), 1);
+        assert!(source.search_artists("nobody", 0, 10).await?.is_empty());
+        assert_eq!(
+            source.artist(1, Priority::Interactive).await?.title,
+            "Fake Artist"
+        );
+        assert!(matches!(
+            source.artist(2, Priority::Interactive).await,
+            Err(Error::NotFound)
+        ));
+
+        let albums = source.artist_albums(1).await?;
+        assert_eq!(albums.len(), 2);
+        let tracks = source.album_tracks(&albums[1]).await?;
+        assert_eq!(tracks[0].album_title, "Second Album");
+        assert_eq!(tracks[0].album_release_date, albums[1].release_date);
+        assert_eq!(tracks[1].track_position, Some(2));
+        assert_eq!(
+            source.preview_url(111).await?,
+            "https://example.com/preview/111.mp3"
+        );
+        Ok(())
+    }
+}
@@ -1,38 +1,68 @@
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use crate::{
-    db::{AlbumInfo, ArtistInfo, TrackInfo},
-    deezer::{self, Artist, Deezer, PaginatedResponse},
-    loading::Loading,
+    db::{AlbumInfo, ArtistInfo, LeaderboardEntry, TrackInfo, UnusedArtist, Upsert},
+    deezer, deser_timedelta,
+    loading::{Loading, LoadingError, Progress},
+    quiz::{group_by_title, AnswerResult, QuizSettings, SessionError, SessionView, Sessions},
+    source::{self, MusicSource, Priority},
+    titles::{TitleConfig, TitleNormalizer},
+    trackcache::{TrackCache, TrackCacheConfig, TrackCacheStats, TrackGroups},
 };
-use chrono::{TimeDelta, Utc};
-use sqlx::{PgPool, Postgres, Transaction};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
 use thiserror::Error;
+use tokio::sync::watch;
+
+/// The number of albums whose tracks are fetched at once when updating the cache.
+/// Requests are still subject to the source's own rate limiting.
+const ALBUM_FETCH_CONCURRENCY: usize = 8;
 
 #[derive(Debug, Error, Clone)]
 enum CacheUpdateError {
+    #[error("not found")]
+    NotFound,
     #[error("deezer API error")]
     ApiError(#[source] deezer::ApiErrCode),
     #[error("internal error")]
     InternalError,
+    #[error("loading error")]
+    Loading(#[from] LoadingError),
 }
 
 #[derive(Debug, Error)]
 pub enum RetrievalError {
+    #[error("not found")]
+    NotFound,
     #[error("internal cache update error")]
     CacheUpdateInternalError,
     #[error("deezer API error")]
     ApiError(#[from] deezer::ApiErrCode),
     #[error("database error")]
     DbError(#[from] sqlx::Error),
+    #[error("quiz session error")]
+    SessionError(#[from] SessionError),
+    #[error("loading error")]
+    LoadingError(#[from] LoadingError),
 }
 
 impl From<CacheUpdateError> for RetrievalError {
     fn from(err: CacheUpdateError) -> Self {
         use CacheUpdateError::*;
         match err {
+            NotFound => RetrievalError::NotFound,
             ApiError(err) => RetrievalError::ApiError(err),
             InternalError => RetrievalError::CacheUpdateInternalError,
+            Loading(err) => RetrievalError::LoadingError(err),
         }
     }
 }
@@ -54,102 +84,482 @@ fn to_internal_error<E: Error + 'static>(err: E) -> CacheUpdateError {
     CacheUpdateError::InternalError
 }
 
-impl From<deezer::Error> for CacheUpdateError {
-    fn from(value: deezer::Error) -> Self {
-        use deezer::Error;
+impl From<source::Error> for CacheUpdateError {
+    fn from(value: source::Error) -> Self {
+        use source::Error;
         match value {
-            Error::ApiError(e) => CacheUpdateError::ApiError(e),
+            Error::NotFound => CacheUpdateError::NotFound,
+            Error::Deezer(deezer::Error::ApiError(e)) => CacheUpdateError::ApiError(e),
             e => to_internal_error(e),
         }
     }
 }
 
+/// What refreshing an artist in the cache changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefreshReport {
+    pub albums_added: u64,
+    pub albums_updated: u64,
+    pub albums_removed: u64,
+    pub tracks_added: u64,
+    pub tracks_updated: u64,
+    pub tracks_removed: u64,
+    /// Albums whose tracks couldn't be fetched, and were left as they were.
+    pub albums_failed: u64,
+}
+
+impl RefreshReport {
+    fn count_album(&mut self, upsert: Upsert) {
+        match upsert {
+            Upsert::Inserted => self.albums_added += 1,
+            Upsert::Updated => self.albums_updated += 1,
+            Upsert::Unchanged => {}
+        }
+    }
+
+    fn count_track(&mut self, upsert: Upsert) {
+        match upsert {
+            Upsert::Inserted => self.tracks_added += 1,
+            Upsert::Updated => self.tracks_updated += 1,
+            Upsert::Unchanged => {}
+        }
+    }
+}
+
+impl fmt::Display for RefreshReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "albums +{} ~{} -{}, tracks +{} ~{} -{}",
+            self.albums_added,
+            self.albums_updated,
+            self.albums_removed,
+            self.tracks_added,
+            self.tracks_updated,
+            self.tracks_removed
+        )?;
+        if self.albums_failed > 0 {
+            write!(f, ", {} albums failed", self.albums_failed)?;
+        }
+        Ok(())
+    }
+}
+
+/// An artist's tracks right after refreshing it, and what changed.
+#[derive(Debug, Clone)]
+struct Refreshed {
+    tracks: Vec<TrackInfo>,
+    report: RefreshReport,
+}
+
+type CacheResult = Result<(ArtistInfo, Option<Refreshed>), CacheUpdateError>;
+
+/// How far along loading an artist into the cache is.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LoadProgress {
+    /// Albums whose tracks have been fetched, successfully or not.
+    pub albums_fetched: usize,
+    /// The number of albums the artist has, once known.
+    pub albums_total: Option<usize>,
+    /// Tracks written to the cache so far.
+    pub tracks_stored: usize,
+}
+
+/// Configures how long failed loads are remembered. Until they're forgotten,
+/// requests for the same artist fail straight away instead of loading it again.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NegativeCacheConfig {
+    /// How long artists that don't exist are remembered.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub not_found: TimeDelta,
+    /// How long other failures, which are likely to be temporary, are remembered.
+    #[serde(deserialize_with = "deser_timedelta")]
+    pub error: TimeDelta,
+}
+
+impl Default for NegativeCacheConfig {
+    fn default() -> Self {
+        Self {
+            not_found: TimeDelta::try_minutes(10).unwrap(),
+            error: TimeDelta::try_seconds(30).unwrap(),
+        }
+    }
+}
+
+/// Artists that recently failed to load, and how they failed.
+struct FailedLoads {
+    config: NegativeCacheConfig,
+    failures: Mutex<HashMap<u32, (CacheUpdateError, DateTime<Utc>)>>,
+}
+
+impl FailedLoads {
+    fn new(config: NegativeCacheConfig) -> Self {
+        Self {
+            config,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gets how loading `artist` failed, if that is still remembered.
+    fn get(&self, artist: u32) -> Option<CacheUpdateError> {
+        let failures = self.failures.lock().unwrap();
+        match failures.get(&artist) {
+            Some((err, until)) if *until > Utc::now() => Some(err.clone()),
+            _ => None,
+        }
+    }
+
+    /// Remembers the outcome of loading `artist`, forgetting earlier failures
+    /// if it succeeded.
+    fn record<T>(&self, artist: u32, result: &Result<T, CacheUpdateError>) {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Utc::now();
+        failures.retain(|_, (_, until)| *until > now);
+        match result {
+            Ok(_) => {
+                failures.remove(&artist);
+            }
+            Err(err) => {
+                let ttl = match err {
+                    CacheUpdateError::NotFound
+                    | CacheUpdateError::ApiError(deezer::ApiErrCode::DataNotFound) => {
+                        self.config.not_found
+                    }
+                    _ => self.config.error,
+                };
+                failures.insert(artist, (err.clone(), now + ttl));
+            }
+        }
+    }
+}
+
 /// Represents the internal state of the quiz.
 pub struct QuizState {
-    loading: Loading<u32, Result<(ArtistInfo, Option<Vec<TrackInfo>>), CacheUpdateError>>,
+    loading: Loading<u32, CacheResult, LoadProgress>,
+    load_timeout: Option<Duration>,
+    failed: Arc<FailedLoads>,
+    tracks: Arc<TrackCache>,
     pool: PgPool,
     cache_duration: chrono::Duration,
-    deezer: Deezer,
+    cache_max_age: Option<chrono::Duration>,
+    source: Arc<dyn MusicSource>,
+    sessions: Sessions,
+    titles: Arc<TitleNormalizer>,
 }
 
 impl QuizState {
-    /// Createa a new quiz from `conf`.
-    pub fn new(db_address: &str, cache_duration: TimeDelta) -> Result<Self, sqlx::Error> {
-        Ok(Self {
+    /// Createa a new quiz from `conf`, getting music from `source`. Cached artists
+    /// are refreshed after `cache_duration`, and served stale while refreshing
+    /// until `cache_max_age`.
+    pub fn new(
+        db_address: &str,
+        cache_duration: TimeDelta,
+        cache_max_age: Option<TimeDelta>,
+        source: Arc<dyn MusicSource>,
+    ) -> Result<Self, sqlx::Error> {
+        let pool = PgPool::connect_lazy(db_address)?;
+        Ok(Self::with_pool(pool, cache_duration, cache_max_age, source))
+    }
+
+    pub(crate) fn with_pool(
+        pool: PgPool,
+        cache_duration: TimeDelta,
+        cache_max_age: Option<TimeDelta>,
+        source: Arc<dyn MusicSource>,
+    ) -> Self {
+        Self {
             loading: Loading::new(),
-            pool: PgPool::connect_lazy(db_address)?,
+            load_timeout: None,
+            failed: Arc::new(FailedLoads::new(NegativeCacheConfig::default())),
+            tracks: Arc::new(TrackCache::new(TrackCacheConfig::default())),
+            sessions: Sessions::new(pool.clone()),
+            titles: Arc::default(),
+            pool,
             cache_duration,
-            deezer: Deezer::new(),
-        })
+            cache_max_age,
+            source,
+        }
+    }
+
+    /// Remembers failed loads for as long as `config` says.
+    pub fn with_negative_cache(mut self, config: NegativeCacheConfig) -> Self {
+        self.failed = Arc::new(FailedLoads::new(config));
+        self
+    }
+
+    /// Keeps the tracks of as many artists in memory as `config` says.
+    pub fn with_track_cache(mut self, config: TrackCacheConfig) -> Self {
+        self.tracks = Arc::new(TrackCache::new(config));
+        self
+    }
+
+    /// Tells versions of the same song apart as `config` says.
+    pub fn with_titles(mut self, config: &TitleConfig) -> Self {
+        self.titles = Arc::new(TitleNormalizer::new(config));
+        self.sessions = self.sessions.with_titles(Arc::clone(&self.titles));
+        self
+    }
+
+    /// Gives up on loading an artist into the cache once it takes longer than
+    /// `timeout`.
+    pub fn with_load_timeout(mut self, timeout: TimeDelta) -> Self {
+        self.load_timeout = Some(timeout.to_std().unwrap_or_default());
+        self
     }
 
     async fn update_cache(
-        deezer: Deezer,
-        mut trans: Transaction<'_, Postgres>,
+        source: Arc<dyn MusicSource>,
+        pool: PgPool,
         artist_id: u32,
-    ) -> Result<(ArtistInfo, Option<Vec<TrackInfo>>), CacheUpdateError> {
-        let artist = deezer.artist(artist_id).await?;
-        let ainfo = ArtistInfo::from(artist);
-        ainfo.delete(&mut trans).await.map_err(to_internal_error)?;
+        priority: Priority,
+        progress: Progress<LoadProgress>,
+    ) -> CacheResult {
+        let ainfo = source.artist(artist_id, priority).await?;
+        let albums = source.artist_albums(artist_id).await?;
+        progress.update(|p| p.albums_total = Some(albums.len()));
+
+        // fetch everything before touching the database, so the transaction is only
+        // held open for the writes
+        let fetched: Vec<_> = stream::iter(albums)
+            .map(|album| {
+                let source = Arc::clone(&source);
+                let progress = progress.clone();
+                async move {
+                    let album_tracks = source.album_tracks(&album).await;
+                    progress.update(|p| p.albums_fetched += 1);
+                    (album, album_tracks)
+                }
+            })
+            .buffered(ALBUM_FETCH_CONCURRENCY)
+            .collect()
+            .await;
 
-        ainfo.insert(&mut trans).await.map_err(to_internal_error)?;
+        // rather than replacing everything, only what changed is written, so that
+        // rows aren't churned and things fetched only partially aren't lost
+        let mut report = RefreshReport::default();
+        let mut trans = pool.begin().await.map_err(to_internal_error)?;
+        ainfo.upsert(&mut trans).await.map_err(to_internal_error)?;
 
-        let mut tracks = Vec::new();
+        let (albums, album_tracks): (Vec<_>, Vec<_>) = fetched.into_iter().unzip();
+        for upsert in AlbumInfo::upsert_many(&mut trans, &albums)
+            .await
+            .map_err(to_internal_error)?
+        {
+            report.count_album(upsert);
+        }
 
-        for album in deezer.artist_albums(artist_id, 0, 300).await?.data {
-            let album_tracks = match deezer.album_tracks(album.id, 0, 300).await {
-                Ok(t) => t.data,
+        // tracks are only replaced on albums whose tracks could be fetched. they're
+        // written an album at a time, so the loading page can show progress
+        let mut fetched_album_ids = Vec::new();
+        let mut track_ids = Vec::new();
+        for (album, album_tracks) in albums.iter().zip(album_tracks) {
+            match album_tracks {
+                Ok(tracks) => {
+                    for upsert in TrackInfo::upsert_many(&mut trans, &tracks)
+                        .await
+                        .map_err(to_internal_error)?
+                    {
+                        report.count_track(upsert);
+                    }
+                    progress.update(|p| p.tracks_stored += tracks.len());
+                    fetched_album_ids.push(album.id);
+                    track_ids.extend(tracks.iter().map(|t| t.id));
+                }
                 Err(e) => {
                     log::warn!(
                         "Error getting tracks for album {album_id}: {e}",
                         album_id = album.id
                     );
-                    continue;
+                    report.albums_failed += 1;
                 }
-            };
+            }
+        }
 
-            AlbumInfo::from_album(album.clone(), artist_id)
-                .insert(&mut trans)
+        report.tracks_removed +=
+            TrackInfo::delete_missing_from_albums(&mut trans, &fetched_album_ids, &track_ids)
                 .await
                 .map_err(to_internal_error)?;
 
-            for track in album_tracks {
-                let trackinfo = TrackInfo::from_deezer(track, album.clone());
-                trackinfo
-                    .insert(&mut trans)
-                    .await
-                    .map_err(to_internal_error)?;
-                tracks.push(trackinfo);
-            }
-        }
+        let album_ids: Vec<i64> = albums.iter().map(|a| a.id).collect();
+        report.albums_removed += AlbumInfo::delete_missing(&mut trans, ainfo.id, &album_ids)
+            .await
+            .map_err(to_internal_error)?;
 
+        let tracks = TrackInfo::from_artist_id(&mut trans, artist_id)
+            .await
+            .map_err(to_internal_error)?;
         trans.commit().await.map_err(to_internal_error)?;
 
-        Ok((ainfo, Some(tracks)))
+        log::info!("Refreshed artist {artist_id}: {report}");
+        Ok((ainfo, Some(Refreshed { tracks, report })))
     }
-    async fn update_cache_if_needed(
-        &self,
-        artist: u32,
-    ) -> Result<(ArtistInfo, Option<Vec<TrackInfo>>), CacheUpdateError> {
-        let mut trans = self.pool.begin().await.map_err(to_internal_error)?;
-        let artist_opt = ArtistInfo::get_from_id(&mut trans, artist)
+
+    async fn update_cache_if_needed(&self, artist: u32) -> CacheResult {
+        let mut conn = self.pool.acquire().await.map_err(to_internal_error)?;
+        let cached = ArtistInfo::get_from_id(&mut conn, artist)
             .await
-            .map_err(to_internal_error)?
-            .filter(|a| a.updated_at + self.cache_duration > Utc::now());
-
-        match artist_opt {
-            Some(artist) => Ok((artist, None)),
-            None => {
-                self.loading
-                    .run(
-                        artist,
-                        QuizState::update_cache(self.deezer.clone(), trans, artist),
-                    )
+            .map_err(to_internal_error)?;
+        let now = Utc::now();
+        if cached.is_some() {
+            // only recorded once an hour, which is plenty for garbage collection
+            let recorded_since = now - TimeDelta::try_hours(1).unwrap();
+            if let Err(e) = ArtistInfo::touch(&mut conn, artist, now, recorded_since).await {
+                log::warn!("Could not record access to artist {artist}: {e}");
+            }
+        }
+        drop(conn);
+
+        match cached {
+            Some(cached) if cached.updated_at + self.cache_duration > now => Ok((cached, None)),
+            Some(cached)
+                if self
+                    .cache_max_age
+                    .is_none_or(|max_age| cached.updated_at + max_age > now) =>
+            {
+                // don't retry a refresh that just failed on every request
+                if self.failed.get(artist).is_none() {
+                    self.refresh_in_background(artist);
+                }
+                Ok((cached, None))
+            }
+            _ => {
+                if let Some(err) = self.failed.get(artist) {
+                    return Err(err);
+                }
+                // someone is waiting for this one
+                let result = self
+                    .loading
+                    .run_with_progress(artist, self.load_timeout, |progress| {
+                        QuizState::update_cache(
+                            Arc::clone(&self.source),
+                            self.pool.clone(),
+                            artist,
+                            Priority::Interactive,
+                            progress,
+                        )
+                    })
                     .await
+                    .unwrap_or_else(|err| Err(err.into()));
+                self.loaded(artist, &result);
+                result
             }
         }
     }
 
+    /// Takes note of the outcome of loading `artist`: failures are remembered,
+    /// and the tracks kept in memory are dropped if they may have changed.
+    fn record_load(failed: &FailedLoads, tracks: &TrackCache, artist: u32, result: &CacheResult) {
+        failed.record(artist, result);
+        if result.is_ok() {
+            tracks.invalidate(artist);
+        }
+    }
+
+    fn loaded(&self, artist: u32, result: &CacheResult) {
+        QuizState::record_load(&self.failed, &self.tracks, artist, result);
+    }
+
+    /// Refreshes the artist with id `artist` without waiting for it to finish,
+    /// unless a refresh is already in progress.
+    fn refresh_in_background(&self, artist: u32) {
+        let source = Arc::clone(&self.source);
+        let pool = self.pool.clone();
+        let failed = Arc::clone(&self.failed);
+        let tracks = Arc::clone(&self.tracks);
+        self.loading
+            .spawn_with_progress(artist, self.load_timeout, |progress| async move {
+                let result =
+                    QuizState::update_cache(source, pool, artist, Priority::Background, progress)
+                        .await;
+                if let Err(e) = &result {
+                    log::warn!("Background refresh of artist {artist} failed: {e}");
+                }
+                QuizState::record_load(&failed, &tracks, artist, &result);
+                result
+            });
+    }
+
+    /// Refreshes the artist with id `artist` in the cache, whether or not it's
+    /// stale, and reports what changed. Meant for refreshing ahead of time, so its
+    /// requests give way to ones someone is waiting on.
+    pub async fn refresh_artist(&self, artist: u32) -> Result<RefreshReport, RetrievalError> {
+        let result = self
+            .loading
+            .run_with_progress(artist, self.load_timeout, |progress| {
+                QuizState::update_cache(
+                    Arc::clone(&self.source),
+                    self.pool.clone(),
+                    artist,
+                    Priority::Background,
+                    progress,
+                )
+            })
+            .await
+            .unwrap_or_else(|err| Err(err.into()));
+        self.loaded(artist, &result);
+        let (_, refreshed) = result?;
+        Ok(refreshed.map(|r| r.report).unwrap_or_default())
+    }
+
+    /// Gets up to `limit` cached artists that will have gone stale within `horizon`
+    /// and were played or accessed in the last `popularity_window`, the most played
+    /// first.
+    pub async fn artists_due_for_refresh(
+        &self,
+        horizon: TimeDelta,
+        popularity_window: TimeDelta,
+        limit: usize,
+    ) -> Result<Vec<u32>, sqlx::Error> {
+        let now = Utc::now();
+        let ids = ArtistInfo::due_for_refresh(
+            self.pool.acquire().await?.as_mut(),
+            now + horizon - self.cache_duration,
+            now - popularity_window,
+            limit.try_into().unwrap_or(i64::MAX),
+        )
+        .await?;
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| id.try_into().ok())
+            .collect())
+    }
+
+    /// Watches the progress of loading the artist with id `artist` into the cache,
+    /// if that is in progress.
+    pub fn load_progress(&self, artist: u32) -> Option<watch::Receiver<LoadProgress>> {
+        self.loading.progress(&artist)
+    }
+
+    /// Removes cached artists that weren't accessed within `idle`, and all but the
+    /// `max_artists` most recently accessed ones, unless they're being loaded. Returns
+    /// the artists removed, or with `dry_run`, the ones that would have been.
+    pub async fn collect_garbage(
+        &self,
+        idle: TimeDelta,
+        max_artists: Option<usize>,
+        dry_run: bool,
+    ) -> Result<Vec<UnusedArtist>, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let keep = max_artists.map_or(i64::MAX, |max| max.try_into().unwrap_or(i64::MAX));
+        let mut unused = ArtistInfo::unused(&mut conn, Utc::now() - idle, keep).await?;
+        unused.retain(|a| {
+            u32::try_from(a.id)
+                .ok()
+                .is_none_or(|id| !self.loading.is_loading(&id))
+        });
+        if dry_run {
+            return Ok(unused);
+        }
+
+        let ids: Vec<i64> = unused.iter().map(|a| a.id).collect();
+        ArtistInfo::delete_ids(&mut conn, &ids).await?;
+        for id in ids.into_iter().filter_map(|id| u32::try_from(id).ok()) {
+            self.tracks.invalidate(id);
+        }
+        Ok(unused)
+    }
+
     /// Retrieves artist wtih id `artist`, caching it as needed.
     pub async fn get_artist(&self, artist: u32) -> Result<ArtistInfo, RetrievalError> {
         let (artist, _) = self.update_cache_if_needed(artist).await?;
@@ -159,47 +569,240 @@ impl QuizState {
     /// Retrieves tracks by artist with id `artist`, caching as needed.
     pub async fn get_artist_tracks(&self, artist: u32) -> Result<Vec<TrackInfo>, RetrievalError> {
         match self.update_cache_if_needed(artist).await? {
-            (_, Some(tracks)) => Ok(tracks),
+            (_, Some(refreshed)) => Ok(refreshed.tracks),
             (_, None) => {
                 Ok(TrackInfo::from_artist_id(self.pool.acquire().await?.as_mut(), artist).await?)
             }
         }
     }
 
-    /// Searches for artists with names matching the given query using the Deezer API
+    /// Searches for artists with names matching the given query using the music source
     pub async fn search_artists(
         &self,
         q: &str,
         index: u32,
         limit: u32,
-    ) -> Result<PaginatedResponse<Artist>, deezer::Error> {
-        self.deezer.search_artist(q, index, limit).await
+    ) -> Result<Vec<ArtistInfo>, source::Error> {
+        self.source.search_artists(q, index, limit).await
+    }
+
+    /// Retrieves tracks by artist with id `artist` grouped by title, keeping them
+    /// in memory to make the next quiz quicker to start.
+    async fn get_artist_track_groups(
+        &self,
+        artist: u32,
+    ) -> Result<Arc<TrackGroups>, RetrievalError> {
+        if let (_, Some(refreshed)) = self.update_cache_if_needed(artist).await? {
+            let groups = Arc::new(group_by_title(refreshed.tracks, &self.titles));
+            let epoch = self.tracks.epoch();
+            self.tracks.insert(artist, Arc::clone(&groups), epoch);
+            return Ok(groups);
+        }
+        if let Some(groups) = self.tracks.get(artist) {
+            return Ok(groups);
+        }
+
+        let epoch = self.tracks.epoch();
+        let tracks = TrackInfo::from_artist_id(self.pool.acquire().await?.as_mut(), artist).await?;
+        let groups = Arc::new(group_by_title(tracks, &self.titles));
+        self.tracks.insert(artist, Arc::clone(&groups), epoch);
+        Ok(groups)
+    }
+
+    /// Gets how well keeping artists' tracks in memory has been working.
+    pub fn track_cache_stats(&self) -> TrackCacheStats {
+        self.tracks.stats()
+    }
+
+    /// Starts a new quiz session about the artist with id `artist`, put together
+    /// according to `settings`.
+    pub async fn start_quiz(
+        &self,
+        artist: u32,
+        settings: QuizSettings,
+    ) -> Result<SessionView, RetrievalError> {
+        let groups = self.get_artist_track_groups(artist).await?;
+        Ok(self
+            .sessions
+            .create_from_groups(artist, &groups, settings)
+            .await?)
+    }
+
+    /// Retrieves the state of `session`, so that it can be resumed.
+    pub async fn resume_quiz(&self, session: &str) -> Result<SessionView, SessionError> {
+        self.sessions.resume(session).await
+    }
+
+    /// Marks question `token` in `session` as asked, returning the id of the track to be guessed.
+    pub async fn ask(&self, session: &str, token: &str) -> Result<i64, SessionError> {
+        self.sessions.ask(session, token).await
+    }
+
+    /// Checks `answer` to question `token` in `session`.
+    pub async fn answer(
+        &self,
+        session: &str,
+        token: &str,
+        answer: &str,
+    ) -> Result<AnswerResult, SessionError> {
+        self.sessions.answer(session, token, answer).await
+    }
+
+    /// Marks `session` as finished.
+    pub async fn finish_quiz(&self, session: &str) -> Result<(), SessionError> {
+        self.sessions.finish(session).await
+    }
+
+    /// Submits the finished `session` to the leaderboard under `name`.
+    pub async fn submit_name(&self, session: &str, name: &str) -> Result<(), SessionError> {
+        self.sessions.submit_name(session, name).await
+    }
+
+    /// Retrieves the best `limit` leaderboard entries for the artist with id `artist`,
+    /// out of the quizzes played with `settings`.
+    pub async fn leaderboard(
+        &self,
+        artist: u32,
+        settings: QuizSettings,
+        limit: i64,
+    ) -> Result<Vec<LeaderboardEntry>, sqlx::Error> {
+        LeaderboardEntry::for_artist(
+            self.pool.acquire().await?.as_mut(),
+            artist,
+            settings.difficulty,
+            settings.distractors,
+            limit,
+        )
+        .await
+    }
+
+    /// Retrieves a fresh preview URL for the track with id `track`, as the ones
+    /// stored in the database expire after a while.
+    pub async fn track_preview(&self, track: i64) -> Result<String, source::Error> {
+        self.source.preview_url(track).await
+    }
+
+    /// Gets the path of the audio file for the track with id `track`, if previews are
+    /// served from the local library.
+    pub fn local_track_path(&self, track: i64) -> Option<PathBuf> {
+        self.source.local_path(track)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use serial_test::serial;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use rand::{rngs::StdRng, SeedableRng};
+    use sqlx::Postgres;
     use tokio::select;
+    use url::Url;
 
     use super::*;
+    use crate::deezer::fixtures::{FixtureMode, Fixtures};
+    use crate::deezer::mock::{Failure, MockDeezer};
+    use crate::deezer::tests::fast_retry;
+    use crate::deezer::Deezer;
+    use crate::quiz::generate_questions;
+    use crate::source::tests::fake_source;
+    use crate::source::{FakeAlbum, FakeArtist, FakeSource, FakeTrack};
 
-    async fn setup_state(pool: sqlx::Pool<Postgres>) -> QuizState {
-        eprintln!("waiting for 5 seconds to clear Deezer ratelimit");
-        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-        eprintln!("wait complete");
-        QuizState {
-            loading: Loading::new(),
-            pool,
-            cache_duration: TimeDelta::try_minutes(10).unwrap(),
-            deezer: Deezer::new(),
+    /// A source that can be slow or unreliable at listing album tracks, keeping
+    /// track of how many listings are in progress at once, and of how urgently
+    /// artists were asked for.
+    struct TestSource {
+        inner: FakeSource,
+        delay: Duration,
+        failing_album: Option<i64>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+        priorities: Mutex<Vec<Priority>>,
+    }
+
+    impl TestSource {
+        fn new(inner: FakeSource) -> Self {
+            Self {
+                inner,
+                delay: Duration::ZERO,
+                failing_album: None,
+                in_flight: AtomicUsize::new(0),
+                max_in_flight: AtomicUsize::new(0),
+                priorities: Mutex::default(),
+            }
         }
     }
 
+    #[async_trait]
+    impl MusicSource for TestSource {
+        async fn search_artists(
+            &self,
+            q: &str,
+            index: u32,
+            limit: u32,
+        ) -> Result<Vec<ArtistInfo>, source::Error> {
+            self.inner.search_artists(q, index, limit).await
+        }
+
+        async fn artist(&self, id: u32, priority: Priority) -> Result<ArtistInfo, source::Error> {
+            self.priorities.lock().unwrap().push(priority);
+            self.inner.artist(id, priority).await
+        }
+
+        async fn artist_albums(&self, id: u32) -> Result<Vec<AlbumInfo>, source::Error> {
+            self.inner.artist_albums(id).await
+        }
+
+        async fn album_tracks(&self, album: &AlbumInfo) -> Result<Vec<TrackInfo>, source::Error> {
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            if self.failing_album == Some(album.id) {
+                return Err(source::Error::NotFound);
+            }
+            self.inner.album_tracks(album).await
+        }
+
+        async fn preview_url(&self, track: i64) -> Result<String, source::Error> {
+            self.inner.preview_url(track).await
+        }
+    }
+
+    /// Sets up a state backed by a [`MockDeezer`], which has to be kept alive
+    /// for as long as the state is used.
+    async fn setup_state(pool: sqlx::Pool<Postgres>) -> (QuizState, MockDeezer) {
+        let mock = MockDeezer::start().await;
+        let state = QuizState::with_pool(
+            pool,
+            TimeDelta::try_minutes(10).unwrap(),
+            None,
+            Arc::new(mock.client()),
+        );
+        (state, mock)
+    }
+
+    /// Sets up a state replaying the catalogue recorded in `fixtures/deezer/mili`.
+    fn replay_state(pool: sqlx::Pool<Postgres>) -> QuizState {
+        let fixtures = Fixtures::new(
+            FixtureMode::Replay,
+            concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/deezer/mili"),
+        );
+        // nothing listens here, so the test fails if a request wasn't recorded
+        let base = Url::parse("http://127.0.0.1:9").unwrap();
+        QuizState::with_pool(
+            pool,
+            TimeDelta::try_minutes(10).unwrap(),
+            None,
+            Arc::new(Deezer::with_base_url(base).with_fixtures(fixtures)),
+        )
+    }
+
     #[sqlx::test]
-    #[serial]
     async fn test_get_artists(pool: sqlx::Pool<Postgres>) {
-        let state = setup_state(pool).await;
+        let (state, _mock) = setup_state(pool).await;
         let artist = state.get_artist(56563392).await.unwrap();
 
         assert_eq!(artist.title, "Mili");
@@ -213,9 +816,8 @@ mod test {
     }
 
     #[sqlx::test]
-    #[serial]
     async fn test_get_tracks_artist_cached(pool: sqlx::Pool<Postgres>) {
-        let state = setup_state(pool).await;
+        let (state, _mock) = setup_state(pool).await;
         let _artist = state.get_artist(56563392).await.unwrap();
 
         let tracks = select! {
@@ -232,13 +834,480 @@ mod test {
     }
 
     #[sqlx::test]
-    #[serial]
     async fn test_get_tracks_artist_noncached(pool: sqlx::Pool<Postgres>) {
-        let state = setup_state(pool).await;
+        let (state, _mock) = setup_state(pool).await;
         let tracks = state.get_artist_tracks(56563392).await.unwrap();
 
         assert!(tracks
             .into_iter()
             .any(|track| track.title == "Ga1ahad and Scientific Witchery"));
     }
+
+    #[sqlx::test]
+    async fn test_get_tracks_retried(pool: sqlx::Pool<Postgres>) {
+        let (mut state, mock) = setup_state(pool).await;
+        state.source = Arc::new(mock.client().with_retry(fast_retry()));
+
+        mock.fail_next(1, Failure::ServiceBusy);
+        mock.fail_next(1, Failure::Unavailable);
+        let tracks = state.get_artist_tracks(56563392).await.unwrap();
+        assert_eq!(tracks.len(), 12);
+    }
+
+    #[sqlx::test]
+    async fn test_stale_artist_served(pool: sqlx::Pool<Postgres>) {
+        let mock = MockDeezer::start().await;
+        let state = QuizState::with_pool(
+            pool,
+            TimeDelta::zero(),
+            None,
+            Arc::new(mock.client().with_retry(fast_retry())),
+        );
+        assert_eq!(state.get_artist_tracks(56563392).await.unwrap().len(), 12);
+        let requests = mock.request_count();
+
+        // the cache is immediately stale, but is still served while the source is down
+        mock.fail_next(100, Failure::Unavailable);
+        assert_eq!(state.get_artist_tracks(56563392).await.unwrap().len(), 12);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(mock.request_count() > requests);
+    }
+
+    #[sqlx::test]
+    async fn test_load_priority(pool: sqlx::Pool<Postgres>) {
+        let source = Arc::new(TestSource::new(fake_source()));
+        let state = QuizState::with_pool(
+            pool,
+            TimeDelta::zero(),
+            None,
+            Arc::clone(&source) as Arc<dyn MusicSource>,
+        );
+
+        // the first load is waited on, while stale artists are refreshed in the
+        // background, as are ones refreshed ahead of time
+        state.get_artist(1).await.unwrap();
+        state.get_artist(1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        state.refresh_artist(1).await.unwrap();
+        assert_eq!(
+            *source.priorities.lock().unwrap(),
+            [
+                Priority::Interactive,
+                Priority::Background,
+                Priority::Background
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_expired_artist_refreshed(pool: sqlx::Pool<Postgres>) {
+        let mock = MockDeezer::start().await;
+        let state = QuizState::with_pool(
+            pool,
+            TimeDelta::zero(),
+            Some(TimeDelta::zero()),
+            Arc::new(mock.client().with_retry(fast_retry())),
+        );
+        assert_eq!(state.get_artist_tracks(56563392).await.unwrap().len(), 12);
+
+        // past the max age, requests wait for the refresh
+        mock.fail_next(100, Failure::Unavailable);
+        assert!(state.get_artist_tracks(56563392).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_track_cache(pool: sqlx::Pool<Postgres>) {
+        let mut state = QuizState::with_pool(
+            pool,
+            TimeDelta::try_minutes(10).unwrap(),
+            None,
+            Arc::new(fake_source()),
+        );
+        assert_eq!(
+            state
+                .start_quiz(1, QuizSettings::default())
+                .await
+                .unwrap()
+                .questions
+                .len(),
+            4
+        );
+        assert_eq!(
+            state
+                .start_quiz(1, QuizSettings::default())
+                .await
+                .unwrap()
+                .questions
+                .len(),
+            4
+        );
+        let stats = state.track_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 0, 1));
+
+        // refreshing drops the cached tracks
+        let mut source = fake_source();
+        source.artists[0].albums[0].tracks.pop();
+        state.source = Arc::new(source);
+        state.refresh_artist(1).await.unwrap();
+        assert_eq!(state.track_cache_stats().entries, 0);
+
+        assert_eq!(
+            state
+                .start_quiz(1, QuizSettings::default())
+                .await
+                .unwrap()
+                .questions
+                .len(),
+            3
+        );
+        assert_eq!(
+            state
+                .start_quiz(1, QuizSettings::default())
+                .await
+                .unwrap()
+                .questions
+                .len(),
+            3
+        );
+        let stats = state.track_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 1));
+    }
+
+    #[sqlx::test]
+    async fn test_refresh_diff(pool: sqlx::Pool<Postgres>) {
+        let mut state = QuizState::with_pool(
+            pool,
+            TimeDelta::try_minutes(10).unwrap(),
+            None,
+            Arc::new(fake_source()),
+        );
+        let report = state.refresh_artist(1).await.unwrap();
+        assert_eq!(
+            report,
+            RefreshReport {
+                albums_added: 2,
+                tracks_added: 4,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            state.refresh_artist(1).await.unwrap(),
+            RefreshReport::default()
+        );
+
+        // rename a track, drop another, and replace the second album
+        let mut source = fake_source();
+        let albums = &mut source.artists[0].albums;
+        albums[0].tracks[0].title = "Opening (Remastered)".to_owned();
+        albums[0].tracks.pop();
+        albums[1] = FakeAlbum {
+            id: 12,
+            title: "Third Album".to_owned(),
+            cover_url: String::new(),
+            tracks: vec![FakeTrack {
+                id: 120,
+                title: "Encore".to_owned(),
+                preview_url: String::new(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        state.source = Arc::new(source);
+
+        let report = state.refresh_artist(1).await.unwrap();
+        assert_eq!(
+            report,
+            RefreshReport {
+                albums_added: 1,
+                albums_removed: 1,
+                tracks_added: 1,
+                tracks_updated: 1,
+                tracks_removed: 1,
+                ..Default::default()
+            }
+        );
+        let mut titles: Vec<_> = state
+            .get_artist_tracks(1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|t| t.title)
+            .collect();
+        titles.sort();
+        assert_eq!(titles, ["Encore", "Opening (Remastered)"]);
+    }
+
+    #[sqlx::test]
+    async fn test_partial_refresh(pool: sqlx::Pool<Postgres>) {
+        let mut state = QuizState::with_pool(
+            pool,
+            TimeDelta::try_minutes(10).unwrap(),
+            None,
+            Arc::new(fake_source()),
+        );
+        state.refresh_artist(1).await.unwrap();
+
+        // tracks of an album that couldn't be listed are kept
+        let mut source = fake_source();
+        source.artists[0].albums[0].tracks.clear();
+        state.source = Arc::new(TestSource {
+            failing_album: Some(10),
+            ..TestSource::new(source)
+        });
+        let report = state.refresh_artist(1).await.unwrap();
+        assert_eq!(
+            report,
+            RefreshReport {
+                albums_failed: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(state.get_artist_tracks(1).await.unwrap().len(), 4);
+    }
+
+    #[sqlx::test]
+    async fn test_album_tracks_fetched_concurrently(pool: sqlx::Pool<Postgres>) {
+        let albums = (0..20)
+            .map(|i| FakeAlbum {
+                id: 100 + i,
+                title: format!("Album {i}"),
+                cover_url: String::new(),
+                tracks: vec![FakeTrack {
+                    id: 1000 + i,
+                    title: format!("Track {i}"),
+                    preview_url: String::new(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+            .collect();
+        let source = Arc::new(TestSource {
+            delay: Duration::from_millis(50),
+            ..TestSource::new(FakeSource::new(vec![FakeArtist {
+                id: 1,
+                title: "Prolific Artist".to_owned(),
+                icon_url: String::new(),
+                albums,
+            }]))
+        });
+        let state = QuizState::with_pool(
+            pool,
+            TimeDelta::try_minutes(10).unwrap(),
+            None,
+            Arc::clone(&source) as Arc<dyn MusicSource>,
+        );
+
+        let tracks = state.get_artist_tracks(1).await.unwrap();
+        assert_eq!(tracks.len(), 20);
+        // albums come back in order, despite being fetched out of order
+        assert_eq!(tracks[0].title, "Track 0");
+        assert_eq!(tracks[19].title, "Track 19");
+        assert_eq!(
+            source.max_in_flight.load(Ordering::SeqCst),
+            ALBUM_FETCH_CONCURRENCY
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_failed_load_remembered(pool: sqlx::Pool<Postgres>) {
+        let (state, mock) = setup_state(pool).await;
+        assert!(matches!(
+            state.get_artist(1).await,
+            Err(RetrievalError::NotFound)
+        ));
+        let requests = mock.request_count();
+        assert!(matches!(
+            state.get_artist(1).await,
+            Err(RetrievalError::NotFound)
+        ));
+        assert_eq!(mock.request_count(), requests);
+
+        // other artists are unaffected
+        assert!(state.get_artist(56563392).await.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn test_failed_load_forgotten(pool: sqlx::Pool<Postgres>) {
+        let (mut state, mock) = setup_state(pool).await;
+        state.source = Arc::new(mock.client().with_retry(fast_retry()));
+        let state = state.with_negative_cache(NegativeCacheConfig {
+            not_found: TimeDelta::try_minutes(10).unwrap(),
+            error: TimeDelta::zero(),
+        });
+
+        // transient errors are forgotten sooner than missing artists
+        mock.fail_next(fast_retry().attempts as usize, Failure::Unavailable);
+        assert!(state.get_artist(56563392).await.is_err());
+        assert!(state.get_artist(56563392).await.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn test_load_timeout(pool: sqlx::Pool<Postgres>) {
+        let source = TestSource {
+            delay: Duration::from_secs(10),
+            ..TestSource::new(fake_source())
+        };
+        let state = QuizState::with_pool(
+            pool,
+            TimeDelta::try_minutes(10).unwrap(),
+            None,
+            Arc::new(source),
+        )
+        .with_load_timeout(TimeDelta::try_milliseconds(100).unwrap());
+
+        assert!(matches!(
+            state.get_artist_tracks(1).await,
+            Err(RetrievalError::LoadingError(LoadingError::TimedOut))
+        ));
+        assert!(state.load_progress(1).is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_load_progress(pool: sqlx::Pool<Postgres>) {
+        let albums = (0..20)
+            .map(|i| FakeAlbum {
+                id: 100 + i,
+                title: format!("Album {i}"),
+                cover_url: String::new(),
+                tracks: vec![FakeTrack {
+                    id: 1000 + i,
+                    title: format!("Track {i}"),
+                    preview_url: String::new(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+            .collect();
+        let source = Arc::new(TestSource {
+            delay: Duration::from_millis(20),
+            ..TestSource::new(FakeSource::new(vec![FakeArtist {
+                id: 1,
+                title: "Prolific Artist".to_owned(),
+                icon_url: String::new(),
+                albums,
+            }]))
+        });
+        let state = QuizState::with_pool(pool, TimeDelta::try_minutes(10).unwrap(), None, source);
+        assert!(state.load_progress(1).is_none());
+
+        let watch_progress = async {
+            let mut watch = loop {
+                match state.load_progress(1) {
+                    Some(watch) => break watch,
+                    None => tokio::task::yield_now().await,
+                }
+            };
+            let mut seen = vec![watch.borrow_and_update().clone()];
+            while watch.changed().await.is_ok() {
+                seen.push(watch.borrow_and_update().clone());
+            }
+            seen
+        };
+        let (tracks, seen) = tokio::join!(state.get_artist_tracks(1), watch_progress);
+        assert_eq!(tracks.unwrap().len(), 20);
+
+        assert!(seen
+            .windows(2)
+            .all(|w| w[0].albums_fetched <= w[1].albums_fetched
+                && w[0].tracks_stored <= w[1].tracks_stored));
+        assert!(seen
+            .iter()
+            .any(|p| p.albums_total == Some(20) && p.albums_fetched < 20));
+        // tracks are counted as each album is stored, not all at once at the end
+        assert!(seen
+            .iter()
+            .any(|p| p.tracks_stored > 0 && p.tracks_stored < 20));
+        assert_eq!(
+            seen.last(),
+            Some(&LoadProgress {
+                albums_fetched: 20,
+                albums_total: Some(20),
+                tracks_stored: 20,
+            })
+        );
+        assert!(state.load_progress(1).is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_get_tracks_fake_source(pool: sqlx::Pool<Postgres>) {
+        let state = QuizState::with_pool(
+            pool,
+            TimeDelta::try_minutes(10).unwrap(),
+            None,
+            Arc::new(fake_source()),
+        );
+        let tracks = state.get_artist_tracks(1).await.unwrap();
+        assert_eq!(tracks.len(), 4);
+
+        assert!(matches!(
+            state.get_artist(2).await,
+            Err(RetrievalError::NotFound)
+        ));
+    }
+
+    /// The albums and titles of the tracks recorded in `fixtures/deezer/mili`.
+    const MILI_TRACKS: [(&str, &str); 12] = [
+        ("Hue", "Bathtub Mermaid"),
+        ("Hue", "Colorful"),
+        ("Hue", "Sl0t"),
+        ("Mag Mell", "Chocolate Lily"),
+        ("Mag Mell", "Mag Mell (Cranky Remix)"),
+        ("Mag Mell", "Nine Point Eight"),
+        ("Mag Mell", "world.execute(me);"),
+        ("Millennium Mother", "Camellia"),
+        ("Millennium Mother", "Ga1ahad and Scientific Witchery"),
+        ("Millennium Mother", "Poems of a Machine"),
+        ("Millennium Mother", "Rubber Human"),
+        ("Millennium Mother", "Summoning 101"),
+    ];
+
+    #[sqlx::test]
+    async fn test_update_cache_replay(pool: sqlx::Pool<Postgres>) {
+        let state = replay_state(pool);
+        let mut tracks: Vec<_> = state
+            .get_artist_tracks(56563392)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|t| (t.album_title, t.title))
+            .collect();
+        tracks.sort();
+
+        let expected = MILI_TRACKS.map(|(album, title)| (album.to_owned(), title.to_owned()));
+        assert_eq!(tracks, expected);
+    }
+
+    #[sqlx::test]
+    async fn test_questions_replay(pool: sqlx::Pool<Postgres>) {
+        let state = replay_state(pool);
+        let tracks = state.get_artist_tracks(56563392).await.unwrap();
+        let titles: HashSet<_> = MILI_TRACKS.iter().map(|&(_, title)| title).collect();
+
+        for seed in 0..10 {
+            let questions = generate_questions(
+                tracks.clone(),
+                QuizSettings::default(),
+                &TitleNormalizer::default(),
+                &mut StdRng::seed_from_u64(seed),
+            );
+
+            for (track, options) in &questions {
+                assert_eq!(options.len(), 4);
+                assert!(options.contains(&track.title));
+                let unique: HashSet<_> = options.iter().collect();
+                assert_eq!(
+                    unique.len(),
+                    options.len(),
+                    "duplicate options in {options:?}"
+                );
+                assert!(options.iter().all(|o| titles.contains(o.as_str())));
+            }
+            // every song is asked about exactly once
+            let mut answers: Vec<_> = questions.iter().map(|(t, _)| t.title.as_str()).collect();
+            answers.sort();
+            let mut expected: Vec<_> = titles.iter().copied().collect();
+            expected.sort();
+            assert_eq!(answers, expected);
+        }
+    }
 }
@@ -0,0 +1,297 @@
+//! Normalizes track titles, so that different versions of the same song, like
+//! "Song", "Song (Remastered 2011)" and "Song - Live", are recognized as such.
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+/// Configures which parts of a title are taken to describe a version of a song,
+/// rather than being part of its name.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TitleConfig {
+    /// Words marking a bracketed part of a title, or a part after a dash, as
+    /// describing the version, like "remastered" in "Song (Remastered 2011)".
+    /// Matched against whole words, ignoring case.
+    pub version_words: Vec<String>,
+    /// Words starting a list of featured artists, like "feat" in "Song (feat. X)"
+    /// or "Song feat. X". Matched against whole words, ignoring case. Outside of
+    /// brackets they have to be followed by a dot, so "A Feat of Strength" is kept.
+    pub feature_words: Vec<String>,
+}
+
+impl Default for TitleConfig {
+    fn default() -> Self {
+        let words = |words: &[&str]| words.iter().map(|&w| w.to_owned()).collect();
+        Self {
+            version_words: words(&[
+                "acoustic",
+                "bonus",
+                "clean",
+                "deluxe",
+                "demo",
+                "edit",
+                "explicit",
+                "extended",
+                "instrumental",
+                "karaoke",
+                "live",
+                "mix",
+                "mono",
+                "remaster",
+                "remastered",
+                "remix",
+                "stereo",
+                "unplugged",
+                "version",
+            ]),
+            feature_words: words(&["feat", "featuring", "ft"]),
+        }
+    }
+}
+
+/// Turns titles into keys that are the same for all versions of a song.
+#[derive(Debug, Clone)]
+pub struct TitleNormalizer {
+    version_words: HashSet<String>,
+    feature_words: HashSet<String>,
+}
+
+impl Default for TitleNormalizer {
+    fn default() -> Self {
+        Self::new(&TitleConfig::default())
+    }
+}
+
+/// Separators before a part of a title that may describe the version.
+const DASHES: &[&str] = &[" - ", " – ", " — "];
+
+impl TitleNormalizer {
+    pub fn new(config: &TitleConfig) -> Self {
+        let words = |words: &[String]| {
+            words
+                .iter()
+                .map(|w| w.trim_matches('.').to_lowercase())
+                .collect()
+        };
+        Self {
+            version_words: words(&config.version_words),
+            feature_words: words(&config.feature_words),
+        }
+    }
+
+    /// Gets the key of `title`: the title without anything describing its version
+    /// or featured artists, in lowercase and with whitespace collapsed.
+    pub fn key(&self, title: &str) -> String {
+        let title = title.replace(['\u{2018}', '\u{2019}'], "'");
+        let mut stripped = self.strip_brackets(&title);
+
+        while let Some((start, end)) = DASHES
+            .iter()
+            .filter_map(|dash| stripped.rfind(dash).map(|i| (i, i + dash.len())))
+            .max()
+        {
+            if !self.describes_version(&stripped[end..], false) {
+                break;
+            }
+            stripped.truncate(start);
+        }
+
+        let words: Vec<_> = stripped.split_whitespace().collect();
+        let name = match words
+            .iter()
+            .skip(1)
+            .position(|w| self.is_feature_word(w, false))
+        {
+            Some(i) => &words[..=i],
+            None => &words[..],
+        };
+
+        // a title that's nothing but a version, like "(Live)", is kept whole
+        if name.is_empty() {
+            return title
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase();
+        }
+        name.join(" ").to_lowercase()
+    }
+
+    /// Removes the bracketed parts of `title` that describe the version.
+    fn strip_brackets(&self, title: &str) -> String {
+        let mut stripped = String::with_capacity(title.len());
+        let mut rest = title;
+        while let Some(open) = rest.find(['(', '[', '{']) {
+            let close = match &rest[open..open + 1] {
+                "(" => ')',
+                "[" => ']',
+                _ => '}',
+            };
+            let Some(len) = rest[open + 1..].find(close) else {
+                break;
+            };
+            let end = open + 1 + len + 1;
+            if self.describes_version(&rest[open + 1..end - 1], true) {
+                stripped.push_str(&rest[..open]);
+                stripped.push(' ');
+            } else {
+                stripped.push_str(&rest[..end]);
+            }
+            rest = &rest[end..];
+        }
+        stripped.push_str(rest);
+        stripped
+    }
+
+    /// Whether `part` of a title, `bracketed` or not, describes its version or
+    /// featured artists.
+    fn describes_version(&self, part: &str, bracketed: bool) -> bool {
+        if part
+            .split_whitespace()
+            .next()
+            .is_some_and(|first| self.is_feature_word(first, bracketed))
+        {
+            return true;
+        }
+        part.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .any(|w| self.version_words.contains(&w.to_lowercase()))
+    }
+
+    /// Whether `word` starts a list of featured artists. Outside of brackets, only
+    /// abbreviations like "feat." count, as the full words are common in song names.
+    fn is_feature_word(&self, word: &str, bracketed: bool) -> bool {
+        let word = match word.strip_suffix('.') {
+            Some(word) => word,
+            None if bracketed => word,
+            None => return false,
+        };
+        self.feature_words.contains(&word.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let titles = TitleNormalizer::default();
+        let cases = [
+            // nothing to strip
+            ("Song", "song"),
+            ("  Song   Title ", "song title"),
+            ("SONG", "song"),
+            ("world.execute(me);", "world.execute(me);"),
+            (
+                "Ga1ahad and Scientific Witchery",
+                "ga1ahad and scientific witchery",
+            ),
+            ("(Don't Fear) The Reaper", "(don't fear) the reaper"),
+            ("Song (Interlude)", "song (interlude)"),
+            ("Song (Part 2)", "song (part 2)"),
+            ("Song - Part 2", "song - part 2"),
+            ("Anti-Hero", "anti-hero"),
+            ("Dancing with Myself", "dancing with myself"),
+            ("Nine Lives (Alive)", "nine lives (alive)"),
+            ("Left Behind", "left behind"),
+            // remasters
+            ("Song (Remastered 2011)", "song"),
+            ("Song (2011 Remaster)", "song"),
+            ("Song [Remastered]", "song"),
+            ("Song - Remastered 2011", "song"),
+            ("Song - 2009 Remaster", "song"),
+            // live versions
+            ("Song - Live", "song"),
+            ("Song (Live)", "song"),
+            ("Song (Live at Wembley)", "song"),
+            ("Song - Live at Wembley, 1986", "song"),
+            // featured artists
+            ("Song (feat. X)", "song"),
+            ("Song (Feat. X & Y)", "song"),
+            ("Song [ft. X]", "song"),
+            ("Song (featuring X)", "song"),
+            ("Song feat. X", "song"),
+            ("Song ft. X", "song"),
+            ("Song - feat. X", "song"),
+            ("A Feat of Strength", "a feat of strength"),
+            ("Heroic Feat", "heroic feat"),
+            ("Song Featuring X and Y", "song featuring x and y"),
+            ("Song - Feat of Strength", "song - feat of strength"),
+            ("Song (Feat of Strength)", "song"),
+            // other versions
+            ("Song [Instrumental]", "song"),
+            ("Song (Acoustic Version)", "song"),
+            ("Song (Radio Edit)", "song"),
+            ("Song - Radio Edit", "song"),
+            ("Song (Extended Mix)", "song"),
+            ("Mag Mell (Cranky Remix)", "mag mell"),
+            ("Song (Demo)", "song"),
+            ("Song (Mono)", "song"),
+            ("Song {Bonus Track}", "song"),
+            ("Song (Taylor's Version)", "song"),
+            ("Song (Taylor\u{2019}s Version)", "song"),
+            ("Song – Live", "song"),
+            ("Song — Acoustic", "song"),
+            // several at once
+            ("Song (feat. X) [Remastered 2011]", "song"),
+            ("Song (feat. X) - Live", "song"),
+            ("Song - Live - Remastered", "song"),
+            ("Song (Interlude) [Live]", "song (interlude)"),
+            ("Song - Part 2 - Live", "song - part 2"),
+            // only a version
+            ("(Live)", "(live)"),
+            ("Live", "live"),
+            ("Remix", "remix"),
+            ("feat. X", "feat. x"),
+            // unbalanced brackets
+            ("Song (Live", "song (live"),
+            ("Song) (Live)", "song)"),
+        ];
+        for (title, key) in cases {
+            assert_eq!(titles.key(title), key, "key of {title:?}");
+        }
+    }
+
+    #[test]
+    fn test_same_key() {
+        let titles = TitleNormalizer::default();
+        let versions = [
+            "Song",
+            "Song (Remastered 2011)",
+            "Song - Live",
+            "Song (feat. X)",
+            "Song [Instrumental]",
+            "song",
+        ];
+        for a in versions {
+            for b in versions {
+                assert_eq!(titles.key(a), titles.key(b), "{a:?} and {b:?}");
+            }
+        }
+        assert_ne!(titles.key("Song"), titles.key("Song (Part 2)"));
+        assert_ne!(titles.key("Song"), titles.key("Another Song"));
+    }
+
+    #[test]
+    fn test_config() {
+        let titles = TitleNormalizer::new(&TitleConfig {
+            version_words: vec!["Reprise".to_owned()],
+            feature_words: vec!["with".to_owned()],
+        });
+        assert_eq!(titles.key("Song (Reprise)"), "song");
+        assert_eq!(titles.key("Song (with X)"), "song");
+        assert_eq!(titles.key("Song (Live)"), "song (live)");
+        assert_eq!(titles.key("Song feat. X"), "song feat. x");
+
+        let titles = TitleNormalizer::new(&TitleConfig {
+            version_words: Vec::new(),
+            feature_words: Vec::new(),
+        });
+        assert_eq!(
+            titles.key("Song (Remastered 2011)"),
+            "song (remastered 2011)"
+        );
+    }
+}
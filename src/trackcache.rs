@@ -0,0 +1,258 @@
+//! An in-memory cache of the tracks of recently played artists, so that starting
+//! a quiz doesn't have to go to the database every time.
+
+use std::{
+    fmt, mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lru::LruCache;
+use serde::Deserialize;
+
+use crate::db::TrackInfo;
+
+/// The tracks of an artist, grouped by title as by [`crate::quiz::group_by_title`].
+pub type TrackGroups = Vec<Vec<TrackInfo>>;
+
+/// Configures how many artists' tracks are kept in memory.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TrackCacheConfig {
+    /// The most artists kept at once.
+    pub entries: usize,
+    /// Roughly how many bytes the cached tracks may take up in total.
+    pub max_bytes: usize,
+}
+
+impl Default for TrackCacheConfig {
+    fn default() -> Self {
+        Self {
+            entries: 1000,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// How well the cache has been doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrackCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for TrackCacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} artists, {} KiB",
+            self.hits,
+            self.misses,
+            self.entries,
+            self.bytes / 1024
+        )
+    }
+}
+
+struct Entry {
+    groups: Arc<TrackGroups>,
+    bytes: usize,
+}
+
+struct Inner {
+    entries: LruCache<u32, Entry>,
+    bytes: usize,
+}
+
+/// A least recently used cache of [`TrackGroups`] by artist id, bounded both by
+/// the number of artists and by approximate memory use.
+pub struct TrackCache {
+    config: TrackCacheConfig,
+    inner: Mutex<Inner>,
+    /// Bumped on every invalidation, see [`TrackCache::insert`].
+    epoch: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Estimates how much memory `groups` takes up.
+fn size_of_groups(groups: &TrackGroups) -> usize {
+    groups
+        .iter()
+        .flatten()
+        .map(|t| {
+            mem::size_of::<TrackInfo>()
+                + t.album_title.len()
+                + t.album_cover_url.len()
+                + t.title.len()
+                + t.preview_url.len()
+                + t.isrc.as_ref().map_or(0, String::len)
+        })
+        .sum::<usize>()
+        + groups.len() * mem::size_of::<Vec<TrackInfo>>()
+}
+
+impl TrackCache {
+    pub fn new(config: TrackCacheConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                entries: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            epoch: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Gets the tracks of `artist`, if they are cached.
+    pub fn get(&self, artist: u32) -> Option<Arc<TrackGroups>> {
+        let groups = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .get(&artist)
+            .map(|e| Arc::clone(&e.groups));
+        let counter = match groups {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        groups
+    }
+
+    /// Gets the current epoch, which has to be passed to [`TrackCache::insert`].
+    /// It should be taken before reading the tracks to be inserted.
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Caches `groups` as the tracks of `artist`, evicting the least recently used
+    /// artists to make room. Nothing is cached if anything was invalidated since
+    /// `epoch`, as `groups` may then already be out of date.
+    pub fn insert(&self, artist: u32, groups: Arc<TrackGroups>, epoch: u64) {
+        let bytes = size_of_groups(&groups);
+        if self.config.entries == 0 || bytes > self.config.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        // checked under the lock, as invalidating takes it too
+        if self.epoch() != epoch {
+            return;
+        }
+        if let Some(old) = inner.entries.put(artist, Entry { groups, bytes }) {
+            inner.bytes -= old.bytes;
+        }
+        inner.bytes += bytes;
+
+        while inner.entries.len() > self.config.entries || inner.bytes > self.config.max_bytes {
+            match inner.entries.pop_lru() {
+                Some((_, evicted)) => inner.bytes -= evicted.bytes,
+                None => break,
+            }
+        }
+    }
+
+    /// Forgets the tracks of `artist`, e.g. because they have changed.
+    pub fn invalidate(&self, artist: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        if let Some(old) = inner.entries.pop(&artist) {
+            inner.bytes -= old.bytes;
+        }
+    }
+
+    pub fn stats(&self) -> TrackCacheStats {
+        let inner = self.inner.lock().unwrap();
+        TrackCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(n: i64) -> Arc<TrackGroups> {
+        Arc::new(
+            (0..n)
+                .map(|id| vec![TrackInfo::for_test(id, &format!("Track {id}"))])
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let cache = TrackCache::new(TrackCacheConfig::default());
+        assert!(cache.get(1).is_none());
+        cache.insert(1, groups(3), cache.epoch());
+        assert_eq!(cache.get(1).unwrap().len(), 3);
+        assert_eq!(cache.get(1).unwrap().len(), 3);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 1));
+        assert_eq!(stats.bytes, size_of_groups(&groups(3)));
+    }
+
+    #[test]
+    fn test_entry_limit() {
+        let cache = TrackCache::new(TrackCacheConfig {
+            entries: 2,
+            ..Default::default()
+        });
+        cache.insert(1, groups(1), cache.epoch());
+        cache.insert(2, groups(1), cache.epoch());
+        // makes 2 the least recently used
+        cache.get(1);
+        cache.insert(3, groups(1), cache.epoch());
+
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(3).is_some());
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let cache = TrackCache::new(TrackCacheConfig {
+            entries: 100,
+            max_bytes: size_of_groups(&groups(10)) * 2,
+        });
+        cache.insert(1, groups(10), cache.epoch());
+        cache.insert(2, groups(10), cache.epoch());
+        cache.insert(3, groups(10), cache.epoch());
+        assert_eq!(cache.stats().entries, 2);
+        assert!(cache.get(1).is_none());
+        assert!(cache.stats().bytes <= size_of_groups(&groups(10)) * 2);
+
+        // too big to be cached at all
+        cache.insert(4, groups(30), cache.epoch());
+        assert!(cache.get(4).is_none());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = TrackCache::new(TrackCacheConfig::default());
+        cache.insert(1, groups(1), cache.epoch());
+        let epoch = cache.epoch();
+        cache.invalidate(1);
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.stats().bytes, 0);
+
+        // tracks read before the invalidation aren't cached
+        cache.insert(1, groups(1), epoch);
+        assert!(cache.get(1).is_none());
+        cache.insert(1, groups(1), cache.epoch());
+        assert!(cache.get(1).is_some());
+    }
+}
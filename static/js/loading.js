@@ -0,0 +1,26 @@
+// follows the progress of loading an artist, and opens the page once it's done
+window.addEventListener("load", function() {
+  const bar = document.querySelector("#loading-progress");
+  const status = document.querySelector("#loading-status");
+  const events = new EventSource(bar.dataset.src);
+
+  events.addEventListener("progress", e => {
+    const progress = JSON.parse(e.data);
+    if (progress.albums_total === null)
+      return;
+    bar.max = Math.max(progress.albums_total, 1);
+    bar.value = progress.albums_fetched;
+    status.textContent = `${progress.albums_fetched} of ${progress.albums_total} albums fetched, ${progress.tracks_stored} tracks stored`;
+  });
+
+  events.addEventListener("done", () => {
+    events.close();
+    location.reload();
+  });
+
+  // fall back to reloading every now and then if the stream breaks
+  events.addEventListener("error", () => {
+    events.close();
+    setTimeout(() => location.reload(), 5000);
+  });
+});
@@ -1,4 +1,4 @@
-const get_questions = () => fetch(`/artist/${ARTIST_ID}/questions.json`)
+const get_questions = settings => fetch(`/artist/${ARTIST_ID}/questions.json?${new URLSearchParams(settings)}`)
       .then(res => {
         if (!res.ok)
           throw new Error("An internal error occurred.");
@@ -7,23 +7,47 @@ const get_questions = () => fetch(`/artist/${ARTIST_ID}/questions.json`)
       })
       .then(res => res.json());
 
-let questions = get_questions();
+const SESSION_KEY = `quiz-session-${ARTIST_ID}`;
+
+// resumes an interrupted quiz if there is one, otherwise starts a new one
+async function load_quiz() {
+  const stored = localStorage.getItem(SESSION_KEY);
+  if (stored !== null) {
+    const res = await fetch(`/quiz/${encodeURIComponent(stored)}`);
+    if (res.ok) {
+      const state = await res.json();
+      if (!state.finished && state.questions.length > 0)
+        return state;
+    }
+    localStorage.removeItem(SESSION_KEY);
+  }
+
+  const state = await get_questions({
+    difficulty: document.querySelector("#quiz-difficulty").value,
+    distractors: document.querySelector("#quiz-distractors").value,
+  });
+  localStorage.setItem(SESSION_KEY, state.session);
+  return state;
+}
+
+async function finish_quiz(session) {
+  localStorage.removeItem(SESSION_KEY);
+  await fetch(`/quiz/${encodeURIComponent(session)}/finish`, { method: "POST" });
+}
 
 function wait_event(el, evtyp) {
   return new Promise(resolve => el.addEventListener(evtyp, resolve, { once: true }));
 }
 
-// JSONP my beloved
-async function get_track(id) {
-  const promise = new Promise(resolve => deezer_callback = resolve);
-  const element = document.createElement("script");
-  element.src = `https://api.deezer.com/track/${encodeURIComponent(id)}?output=jsonp&callback=deezer_callback`;
-  document.head.append(element);
-  try {
-    return await promise;
-  } finally {
-    element.remove();
-  }
+async function send_answer(session, token, answer) {
+  const res = await fetch(`/quiz/${encodeURIComponent(session)}/answer`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ token, answer }),
+  });
+  if (!res.ok)
+    throw new Error("An internal error occurred.");
+  return await res.json();
 }
 
 class ViewsElement extends HTMLElement {
@@ -157,9 +181,8 @@ class QuizElement extends HTMLElement {
     this.songno = 0;
   }
 
-  async ask(id, options) {
-    const track = await get_track(id);
-    this.#playButton.src = track.preview;
+  async ask(session, token, options) {
+    this.#playButton.src = `/quiz/${encodeURIComponent(session)}/${encodeURIComponent(token)}/preview`;
     this.#playButton.play();
 
     const answer_promises = [];
@@ -234,37 +257,67 @@ class QuizElement extends HTMLElement {
 
 customElements.define("quiz-elem", QuizElement);
 
-async function show_results(score, guessed, total) {
+async function submit_name(session, name) {
+  const res = await fetch(`/quiz/${encodeURIComponent(session)}/name`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ name }),
+  });
+  if (res.status === 409)
+    return "This result has already been submitted.";
+  else if (!res.ok)
+    return "Could not submit your result.";
+  else
+    return "Your result has been submitted!";
+}
+
+async function show_results(session, score, guessed, total, settings) {
   const scorestr = (score == total) ? "PERFECT" : `${score}/${guessed}`;
   document.querySelector("#quiz-final-score").replaceChildren(scorestr);
+  // results only go on the leaderboard for the settings they were played with
+  document.querySelector("#quiz-results .leaderboard-link").href =
+    `/artist/${ARTIST_ID}/leaderboard?${new URLSearchParams(settings)}`;
+
+  const form = document.querySelector("#quiz-name-form");
+  const status = document.querySelector("#quiz-name-status");
+  status.replaceChildren();
+  form.classList.remove("hidden");
+  form.onsubmit = async ev => {
+    ev.preventDefault();
+    const name = document.querySelector("#quiz-name-input").value;
+    status.replaceChildren(await submit_name(session, name));
+    form.classList.add("hidden");
+  };
 
   await document.querySelector("#top-level-views").switch("#quiz-results");
   await wait_event(document.querySelector("#quiz-try-again-btn"), "click");
 }
 
 async function run_quiz() {
-  const qs = await questions;
+  const { session, questions: qs, score, answered, difficulty, distractors } = await load_quiz();
   const quiz = document.querySelector("#quiz");
   const toplevel_views = document.querySelector("#top-level-views");
 
   await toplevel_views.hide();
-  quiz.reset(qs.length);
+  quiz.reset(answered + qs.length);
+  quiz.score = score;
+  quiz.songno = answered;
   await toplevel_views.show("#quiz");
 
   for (const q of qs) {
     quiz.songno++;
-    let user_answer = await quiz.ask(q.answer_info.id, q.options);
-    const correct = q.answer_info.title === user_answer;
-    if (correct) quiz.score++;
+    const user_answer = await quiz.ask(session, q.token, q.options);
+    const { correct, answer_info, score } = await send_answer(session, q.token, user_answer);
+    quiz.score = score;
     const answerOptions = quiz.songno !== quiz.total
             ? [ {label: "Next song",   value: false}
               , {label: "Finish quiz", value: true} ]
             : [ {label: "Finish quiz", value: true} ]
-    if (await quiz.showAnswer(correct, q.answer_info, answerOptions)) break;
+    if (await quiz.showAnswer(correct, answer_info, answerOptions)) break;
   }
 
-  await show_results(quiz.score, quiz.songno, quiz.total);
-  questions = get_questions();
+  await finish_quiz(session);
+  await show_results(session, quiz.score, quiz.songno, quiz.total, { difficulty, distractors });
   return run_quiz();
 }
 